@@ -0,0 +1,191 @@
+use commands;
+use parser::{self, ParseError};
+
+#[derive(Debug, Fail, PartialEq, Eq)]
+pub enum AliasError {
+    #[fail(display = "alias names may only contain letters, digits, - and _")]
+    InvalidName,
+    #[fail(display = "/{} is already a command", _0)]
+    Reserved(String),
+    #[fail(display = "needs at least {} arguments", _0)]
+    MissingArguments(usize),
+    #[fail(display = "line {}: expected an alias name followed by what it expands to", _0)]
+    InvalidLine(usize),
+    #[fail(display = "{}", _0)]
+    Parse(#[cause] ParseError)
+}
+
+/// An alias being used, like `!deploy staging`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Call<'a> {
+    pub sigil: char,
+    pub name: String,
+    /// Everything after the name
+    pub rest: &'a str,
+    /// How many characters come before `rest`, for error columns
+    pub offset: usize
+}
+
+/// Checks an alias name, returning it the way it's stored: lowercase and without a sigil.
+pub fn normalize(name: &str) -> Result<String, AliasError> {
+    let name = name.trim();
+    let name = if name.starts_with('/') || name.starts_with('!') { &name[1..] } else { name };
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        return Err(AliasError::InvalidName);
+    }
+    let name = name.to_lowercase();
+    if commands::find(&name).is_some() {
+        return Err(AliasError::Reserved(name));
+    }
+    Ok(name)
+}
+
+/// Splits `/name rest` or `!name rest`. Returns None for anything else.
+pub fn split(input: &str) -> Option<Call> {
+    let sigil = input.chars().next()?;
+    if sigil != '/' && sigil != '!' {
+        return None;
+    }
+    let after = &input[1..];
+    let end = after.find(char::is_whitespace).unwrap_or(after.len());
+    let name = &after[..end];
+    if name.is_empty() {
+        return None;
+    }
+    let rest = after[end..].trim_left();
+    Some(Call {
+        sigil: sigil,
+        name: name.to_lowercase(),
+        rest: rest.trim_right(),
+        offset: input.chars().count() - rest.chars().count()
+    })
+}
+
+/// Returns the highest `$N` used in `template`.
+fn needed(template: &str) -> usize {
+    let mut needed = 0;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            continue;
+        }
+        match chars.next() {
+            Some(n @ '1'...'9') => needed = needed.max(n as usize - '0' as usize),
+            _ => ()
+        }
+    }
+    needed
+}
+
+/// Fills in `template` for a call:
+///
+/// - `$1` to `$9` are the arguments, quoted again if the template is a `/command` or `!bot` line
+/// - `$*` is everything after the name, as it was typed
+/// - `$$` is a single `$`
+///
+/// Arguments are only parsed if the template uses them,
+/// so an alias for a plain message doesn't choke on a lone quote.
+pub fn expand(template: &str, call: &Call) -> Result<String, AliasError> {
+    let needed = needed(template);
+    let args = if needed > 0 {
+        parser::parse(call.rest).map_err(|err| AliasError::Parse(err.shifted(call.offset)))?
+    } else {
+        Vec::new()
+    };
+    if args.len() < needed {
+        return Err(AliasError::MissingArguments(needed));
+    }
+    let quote = template.starts_with('/') || template.starts_with('!');
+
+    let mut expanded = String::with_capacity(template.len() + call.rest.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.peek().cloned() {
+            Some('$') => {
+                chars.next();
+                expanded.push('$');
+            },
+            Some('*') => {
+                chars.next();
+                expanded.push_str(call.rest);
+            },
+            Some(n @ '1'...'9') => {
+                chars.next();
+                let arg = &args[n as usize - '1' as usize];
+                if quote {
+                    expanded.push_str(&parser::quote(arg));
+                } else {
+                    expanded.push_str(arg);
+                }
+            },
+            _ => expanded.push('$')
+        }
+    }
+    Ok(expanded)
+}
+
+/// Writes aliases as text, one `name expansion` per line.
+pub fn export(aliases: &[(String, String)]) -> String {
+    let mut text = String::from("# synac aliases: name, then what it expands to\n");
+    for &(ref name, ref expansion) in aliases {
+        text.push_str(name);
+        text.push(' ');
+        text.push_str(expansion);
+        text.push('\n');
+    }
+    text
+}
+/// Reads what `export` wrote. Empty lines and lines starting with # are skipped.
+pub fn import(text: &str) -> Result<Vec<(String, String)>, AliasError> {
+    let mut aliases = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let end = line.find(char::is_whitespace).unwrap_or(line.len());
+        let expansion = line[end..].trim();
+        let name = match normalize(&line[..end]) {
+            Ok(ref name) if !expansion.is_empty() => name.clone(),
+            _ => return Err(AliasError::InvalidLine(i + 1))
+        };
+        aliases.push((name, expansion.to_string()));
+    }
+    Ok(aliases)
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    assert_eq!(normalize(" !Deploy "), Ok(String::from("deploy")));
+    assert_eq!(normalize("a b"), Err(AliasError::InvalidName));
+    assert_eq!(normalize("/"), Err(AliasError::InvalidName));
+    assert_eq!(normalize("Join"), Err(AliasError::Reserved(String::from("join"))));
+
+    assert!(split("hello").is_none());
+    assert!(split("/ hi").is_none());
+    let call = split("!Deploy  web 'blue green' ").unwrap();
+    assert_eq!((call.sigil, &*call.name, call.rest, call.offset), ('!', "deploy", "web 'blue green'", 9));
+
+    assert_eq!(expand("!ci deploy $1 --color=$2", &call), Ok(String::from("!ci deploy web --color='blue green'")));
+    assert_eq!(expand("deploying $1 ($2) for $$5", &call), Ok(String::from("deploying web (blue green) for $5")));
+    assert_eq!(expand("/me deploys $* $", &call), Ok(String::from("/me deploys web 'blue green' $")));
+    assert_eq!(expand("!ci $3", &call), Err(AliasError::MissingArguments(3)));
+
+    let call = split("/shrug don't \"panic").unwrap();
+    assert_eq!(expand("$* ¯\\_(ツ)_/¯", &call), Ok(String::from("don't \"panic ¯\\_(ツ)_/¯")));
+    assert_eq!(expand("$2", &call), Err(AliasError::Parse(ParseError::UnterminatedQuote(14))));
+
+    let aliases = vec![
+        (String::from("deploy"), String::from("!ci deploy $1")),
+        (String::from("shrug"), String::from("$* ¯\\_(ツ)_/¯"))
+    ];
+    assert_eq!(import(&export(&aliases)), Ok(aliases));
+    assert_eq!(import("\n  # comment\nhi   hello there "), Ok(vec![(String::from("hi"), String::from("hello there"))]));
+    assert_eq!(import("ok fine\nbroken"), Err(AliasError::InvalidLine(2)));
+    assert_eq!(import("help me"), Err(AliasError::InvalidLine(1)));
+}
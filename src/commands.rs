@@ -0,0 +1,189 @@
+use parser::{self, ParseError};
+
+#[derive(Debug)]
+pub struct Command {
+    pub name: &'static str,
+    /// Arguments, as shown by /help
+    pub usage: &'static str,
+    pub description: &'static str,
+    pub min_args: usize,
+    /// None if the command takes the rest of the line as text, like /me
+    pub max_args: Option<usize>
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "clear",
+        usage: "",
+        description: "Clear the messages shown in this channel",
+        min_args: 0,
+        max_args: Some(0)
+    },
+    Command {
+        name: "connect",
+        usage: "<host> <hash>",
+        description: "Add a server and connect to it",
+        min_args: 2,
+        max_args: Some(2)
+    },
+    Command {
+        name: "help",
+        usage: "[command]",
+        description: "List the commands, or explain one",
+        min_args: 0,
+        max_args: Some(1)
+    },
+    Command {
+        name: "join",
+        usage: "<#channel>",
+        description: "Switch to a channel on this server",
+        min_args: 1,
+        max_args: Some(1)
+    },
+    Command {
+        name: "me",
+        usage: "<action>",
+        description: "Send an action, like /me waves",
+        min_args: 1,
+        max_args: None
+    },
+    Command {
+        name: "mute",
+        usage: "",
+        description: "Mute this channel",
+        min_args: 0,
+        max_args: Some(0)
+    },
+    Command {
+        name: "nick",
+        usage: "<name>",
+        description: "Change your nickname on all servers",
+        min_args: 1,
+        max_args: Some(1)
+    },
+    Command {
+        name: "open",
+        usage: "<synac://link>",
+        description: "Open a link to a server, channel or message",
+        min_args: 1,
+        max_args: Some(1)
+    },
+    Command {
+        name: "topic",
+        usage: "[note]",
+        description: "Set or clear a note about this channel, only visible to you",
+        min_args: 0,
+        max_args: None
+    },
+    Command {
+        name: "unmute",
+        usage: "",
+        description: "Unmute this channel",
+        min_args: 0,
+        max_args: Some(0)
+    }
+];
+
+#[derive(Debug, Fail)]
+pub enum CommandError {
+    #[fail(display = "unknown command: /{}", _0)]
+    Unknown(String),
+    #[fail(display = "usage: /{} {}", _0, _1)]
+    Usage(&'static str, &'static str),
+    #[fail(display = "{}", _0)]
+    Parse(#[cause] ParseError)
+}
+
+pub struct Invocation<'a> {
+    pub command: &'static Command,
+    pub args: Vec<String>,
+    /// Everything after the command name, for commands that take text
+    pub text: &'a str
+}
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    let name = name.trim_left_matches('/').to_lowercase();
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// Parses a `/command`. Returns None if `input` isn't one, which includes
+/// anything starting with `//`, meant to be sent with a single slash.
+pub fn parse(input: &str) -> Option<Result<Invocation, CommandError>> {
+    if !input.starts_with('/') || input.starts_with("//") {
+        return None;
+    }
+    let full = input;
+    let input = &input[1..];
+    let end = input.find(char::is_whitespace).unwrap_or(input.len());
+    let (name, text) = (&input[..end], input[end..].trim_left());
+    let offset = full.chars().count() - text.chars().count();
+    let text = text.trim_right();
+
+    let command = match find(name) {
+        Some(command) => command,
+        None => return Some(Err(CommandError::Unknown(name.to_string())))
+    };
+    // Commands taking text shouldn't trip over an apostrophe or a lone quote
+    let args = if command.max_args.is_none() {
+        text.split_whitespace().map(String::from).collect()
+    } else {
+        match parser::parse(text) {
+            Ok(args) => args,
+            Err(err) => return Some(Err(CommandError::Parse(err.shifted(offset))))
+        }
+    };
+    if args.len() < command.min_args || command.max_args.map(|max| args.len() > max).unwrap_or(false) {
+        return Some(Err(CommandError::Usage(command.name, command.usage)));
+    }
+
+    Some(Ok(Invocation {
+        command: command,
+        args: args,
+        text: text
+    }))
+}
+/// Turns `//text` back into `/text`, leaving anything else alone.
+pub fn unescape(input: &str) -> &str {
+    if input.starts_with("//") {
+        &input[1..]
+    } else {
+        input
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    for pair in COMMANDS.windows(2) {
+        assert!(pair[0].name < pair[1].name, "{} is out of order", pair[1].name);
+    }
+
+    let invocation = parse("/JOIN #general").unwrap().unwrap();
+    assert_eq!(invocation.command.name, "join");
+    assert_eq!(invocation.args, &["#general"]);
+
+    let invocation = parse("/me  waves \"hi\" ").unwrap().unwrap();
+    assert_eq!(invocation.text, "waves \"hi\"");
+
+    assert_eq!(parse("/topic").unwrap().unwrap().text, "");
+    assert!(parse("hello").is_none());
+    assert!(parse("//not a command").is_none());
+    assert_eq!(unescape("//not a command"), "/not a command");
+    assert_eq!(unescape("/x"), "/x");
+
+    match parse("/nope") {
+        Some(Err(CommandError::Unknown(name))) => assert_eq!(name, "nope"),
+        _ => panic!("expected an unknown command")
+    }
+    match parse("/connect host") {
+        Some(Err(CommandError::Usage(name, usage))) => assert_eq!((name, usage), ("connect", "<host> <hash>")),
+        _ => panic!("expected a usage error")
+    }
+    assert!(parse("/clear now").unwrap().is_err());
+    match parse("/join  \"#general") {
+        Some(Err(CommandError::Parse(err))) => assert_eq!(err, ParseError::UnterminatedQuote(8)),
+        _ => panic!("expected a parse error")
+    }
+    assert_eq!(parse("/me says \"hi").unwrap().unwrap().args, &["says", "\"hi"]);
+    assert_eq!(find("/help").map(|command| command.name), Some("help"));
+}
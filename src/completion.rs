@@ -0,0 +1,118 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Nick,
+    Channel,
+    Bot,
+    Command
+}
+impl Kind {
+    pub fn sigil(self) -> char {
+        match self {
+            Kind::Nick => '@',
+            Kind::Channel => '#',
+            Kind::Bot => '!',
+            Kind::Command => '/'
+        }
+    }
+}
+
+/// Finds the word being typed right before `cursor`, if it's something that can be completed.
+/// Returns where it starts (at the sigil), what kind of name it is and what's been typed after the sigil.
+/// Bots and commands are only completed at the start of the message, since that's the only place they work.
+pub fn word_at(text: &str, cursor: usize) -> Option<(usize, Kind, &str)> {
+    let before = &text[..cursor];
+    let start = before.char_indices()
+        .rev()
+        .find(|&(_, c)| c.is_whitespace())
+        .map(|(i, c)| i + c.len_utf8())
+        .unwrap_or(0);
+    let word = &before[start..];
+
+    let kind = match word.chars().next()? {
+        '@' => Kind::Nick,
+        '#' => Kind::Channel,
+        '!' if start == 0 => Kind::Bot,
+        '/' if start == 0 => Kind::Command,
+        _ => return None
+    };
+    Some((start, kind, &word[1..]))
+}
+
+/// Returns the names starting with `prefix`, ignoring case, sorted and without duplicates.
+pub fn candidates<'a, I>(prefix: &str, names: I) -> Vec<String>
+    where I: IntoIterator<Item = &'a str>
+{
+    let prefix = prefix.to_lowercase();
+    let mut matches: Vec<String> = names.into_iter()
+        .filter(|name| name.to_lowercase().starts_with(&*prefix))
+        .map(String::from)
+        .collect();
+    matches.sort();
+    matches.dedup();
+    matches.sort_by_key(|name| name.to_lowercase());
+    matches
+}
+
+/// Repeated Tab presses cycling through the candidates for one word.
+#[derive(Debug)]
+pub struct Cycle {
+    /// Where the word starts, in characters
+    start: usize,
+    /// How many characters the word currently takes up
+    len: usize,
+    kind: Kind,
+    candidates: Vec<String>,
+    index: Option<usize>
+}
+impl Cycle {
+    /// `len` is the length of what was typed, sigil included, in characters.
+    pub fn new(start: usize, len: usize, kind: Kind, candidates: Vec<String>) -> Self {
+        Cycle {
+            start: start,
+            len: len,
+            kind: kind,
+            candidates: candidates,
+            index: None
+        }
+    }
+    pub fn candidates(&self) -> &[String] {
+        &self.candidates
+    }
+    /// Switches to the candidate at `index`, returning the character
+    /// range to replace and what to replace it with.
+    pub fn pick(&mut self, index: usize) -> (usize, usize, String) {
+        let text = format!("{}{} ", self.kind.sigil(), self.candidates[index]);
+        let end = self.start + self.len;
+
+        self.index = Some(index);
+        self.len = text.chars().count();
+        (self.start, end, text)
+    }
+    /// Like `pick`, with the candidate after the current one.
+    pub fn advance(&mut self) -> (usize, usize, String) {
+        let index = self.index.map(|i| (i + 1) % self.candidates.len()).unwrap_or(0);
+        self.pick(index)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    assert_eq!(word_at("hi @jo", 6), Some((3, Kind::Nick, "jo")));
+    assert_eq!(word_at("see #", 5), Some((4, Kind::Channel, "")));
+    assert_eq!(word_at("!bo", 3), Some((0, Kind::Bot, "bo")));
+    assert_eq!(word_at("/op", 3), Some((0, Kind::Command, "op")));
+    assert_eq!(word_at("not /op", 7), None);
+    assert_eq!(word_at("hey!", 4), None);
+    assert_eq!(word_at("ü @a", 5), Some((3, Kind::Nick, "a")));
+
+    let names = vec!["Bob", "alice", "bobby", "bob", "Bob"];
+    assert_eq!(candidates("BO", names.iter().cloned()), &["Bob", "bob", "bobby"]);
+    assert!(candidates("z", names.iter().cloned()).is_empty());
+
+    let mut cycle = Cycle::new(3, 3, Kind::Nick, vec![String::from("bob"), String::from("bobby")]);
+    assert_eq!(cycle.advance(), (3, 6, String::from("@bob ")));
+    assert_eq!(cycle.advance(), (3, 8, String::from("@bobby ")));
+    assert_eq!(cycle.advance(), (3, 10, String::from("@bob ")));
+    assert_eq!(cycle.pick(1), (3, 8, String::from("@bobby ")));
+}
@@ -0,0 +1,208 @@
+use alias;
+use failure::Error;
+use rusqlite::Connection as SqlConnection;
+use std::fs;
+use std::path::Path;
+
+pub const VERSION: i64 = 1;
+
+#[derive(Debug, Fail)]
+pub enum ConfigError {
+    #[fail(display = "not a synac settings file")]
+    NotSettings,
+    #[fail(display = "settings file version {} is newer than this client", _0)]
+    UnsupportedVersion(i64)
+}
+
+pub struct Imported {
+    pub servers: usize,
+    pub skipped: Vec<String>,
+    pub style: Option<String>
+}
+
+/// Writes servers, mutes and everything in the `data` table to a new SQLite file.
+/// Tokens are only included when asked to, since they're as good as a password.
+pub fn export(db: &SqlConnection, path: &Path, tokens: bool, style: Option<&str>) -> Result<(), Error> {
+    if path.exists() {
+        // The file chooser already asked about overwriting it.
+        fs::remove_file(path)?;
+    }
+    let file = SqlConnection::open(path)?;
+    file.execute_batch("CREATE TABLE meta (
+                            key     TEXT NOT NULL PRIMARY KEY UNIQUE,
+                            value   TEXT NOT NULL
+                        );
+                        CREATE TABLE data (
+                            key     TEXT NOT NULL PRIMARY KEY UNIQUE,
+                            value   TEXT NOT NULL
+                        );
+                        CREATE TABLE servers (
+                            ip      TEXT NOT NULL PRIMARY KEY UNIQUE,
+                            name    TEXT NOT NULL,
+                            hash    BLOB NOT NULL,
+                            token   TEXT
+                        );
+                        CREATE TABLE muted (
+                            channel INTEGER NOT NULL,
+                            server  TEXT    NOT NULL
+                        );
+                        CREATE TABLE aliases (
+                            name      TEXT NOT NULL PRIMARY KEY UNIQUE,
+                            expansion TEXT NOT NULL
+                        );")?;
+    file.execute("INSERT INTO meta (key, value) VALUES ('version', ?)", &[&VERSION.to_string()])?;
+    if let Some(style) = style {
+        file.execute("INSERT INTO meta (key, value) VALUES ('style', ?)", &[&style])?;
+    }
+
+    // Our private key stays on this machine. Anyone we talk to will be told about a new one.
+    let mut stmt = db.prepare("SELECT key, value FROM data WHERE key != 'private_key'")?;
+    let mut rows = stmt.query(&[])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let key: String = row.get(0);
+        let value: String = row.get(1);
+        file.execute("INSERT INTO data (key, value) VALUES (?, ?)", &[&key, &value])?;
+    }
+
+    let mut stmt = db.prepare("SELECT ip, name, hash, token FROM servers")?;
+    let mut rows = stmt.query(&[])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let ip: String = row.get(0);
+        let name: String = row.get(1);
+        let hash: String = row.get(2);
+        let token: Option<String> = if tokens { row.get(3) } else { None };
+        file.execute(
+            "INSERT INTO servers (ip, name, hash, token) VALUES (?, ?, ?, ?)",
+            &[&ip, &name, &hash, &token]
+        )?;
+    }
+
+    let mut stmt = db.prepare("SELECT channel, server FROM muted")?;
+    let mut rows = stmt.query(&[])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let channel: i64 = row.get(0);
+        let server: String = row.get(1);
+        file.execute("INSERT INTO muted (channel, server) VALUES (?, ?)", &[&channel, &server])?;
+    }
+
+    let mut stmt = db.prepare("SELECT name, expansion FROM aliases")?;
+    let mut rows = stmt.query(&[])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let name: String = row.get(0);
+        let expansion: String = row.get(1);
+        file.execute("INSERT INTO aliases (name, expansion) VALUES (?, ?)", &[&name, &expansion])?;
+    }
+
+    Ok(())
+}
+/// Merges a file written by `export` into the database.
+/// Servers and aliases that already exist are skipped unless `replace` is set,
+/// in which case a missing token in the file keeps the one we have.
+pub fn import(db: &SqlConnection, path: &Path, replace: bool) -> Result<Imported, Error> {
+    let file = SqlConnection::open(path)?;
+
+    let version: String = file.query_row("SELECT value FROM meta WHERE key = 'version'", &[], |row| row.get(0))
+        .map_err(|_| ConfigError::NotSettings)?;
+    let version: i64 = version.parse().map_err(|_| ConfigError::NotSettings)?;
+    if version > VERSION {
+        return Err(ConfigError::UnsupportedVersion(version).into());
+    }
+
+    let mut imported = Imported {
+        servers: 0,
+        skipped: Vec::new(),
+        style: file.query_row("SELECT value FROM meta WHERE key = 'style'", &[], |row| row.get(0)).ok()
+    };
+
+    // All or nothing, so a broken file doesn't leave half its settings behind
+    db.execute_batch("BEGIN TRANSACTION")?;
+    match import_rows(db, &file, replace, &mut imported) {
+        Ok(()) => db.execute_batch("COMMIT")?,
+        Err(err) => {
+            if let Err(err) = db.execute_batch("ROLLBACK") {
+                eprintln!("error rolling back import: {}", err);
+            }
+            return Err(err);
+        }
+    }
+
+    Ok(imported)
+}
+fn import_rows(db: &SqlConnection, file: &SqlConnection, replace: bool, imported: &mut Imported) -> Result<(), Error> {
+    // A settings file mustn't be able to replace who we are
+    let mut stmt = file.prepare("SELECT key, value FROM data WHERE key != 'private_key'")?;
+    let mut rows = stmt.query(&[])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let key: String = row.get(0);
+        let value: String = row.get(1);
+        db.execute("REPLACE INTO data (key, value) VALUES (?, ?)", &[&key, &value])?;
+    }
+
+    let mut stmt = file.prepare("SELECT ip, name, hash, token FROM servers")?;
+    let mut rows = stmt.query(&[])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let ip: String = row.get(0);
+        let name: String = row.get(1);
+        let hash: String = row.get(2);
+        let token: Option<String> = row.get(3);
+
+        let exists: i64 = db.query_row("SELECT COUNT(*) FROM servers WHERE ip = ?", &[&ip], |row| row.get(0))?;
+        if exists != 0 && !replace {
+            imported.skipped.push(name);
+            continue;
+        }
+        db.execute(
+            "REPLACE INTO servers (ip, name, hash, token)
+                VALUES (?, ?, ?, COALESCE(?, (SELECT token FROM servers WHERE ip = ?)))",
+            &[&ip, &name, &hash, &token, &ip]
+        )?;
+        imported.servers += 1;
+    }
+
+    let mut stmt = file.prepare("SELECT channel, server FROM muted")?;
+    let mut rows = stmt.query(&[])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let channel: i64 = row.get(0);
+        let server: String = row.get(1);
+        db.execute(
+            "INSERT INTO muted (channel, server) SELECT ?, ?
+                WHERE NOT EXISTS (SELECT 1 FROM muted WHERE channel = ? AND server = ?)",
+            &[&channel, &server, &channel, &server]
+        )?;
+    }
+
+    // Files from before aliases existed don't have the table
+    if let Ok(mut stmt) = file.prepare("SELECT name, expansion FROM aliases") {
+        let mut rows = stmt.query(&[])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let name: String = row.get(0);
+            let expansion: String = row.get(1);
+
+            // Checked like alias::import does, since a file can say anything
+            let name = match alias::normalize(&name) {
+                Ok(ref name) if !expansion.trim().is_empty() => name.clone(),
+                _ => {
+                    eprintln!("skipping invalid alias {:?}", name);
+                    continue;
+                }
+            };
+            let expansion = expansion.trim();
+            let sql = if replace {
+                "REPLACE INTO aliases (name, expansion) VALUES (?, ?)"
+            } else {
+                "INSERT OR IGNORE INTO aliases (name, expansion) VALUES (?, ?)"
+            };
+            db.execute(sql, &[&name, &expansion])?;
+        }
+    }
+
+    Ok(())
+}
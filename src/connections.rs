@@ -1,10 +1,13 @@
+use chrono::Utc;
 use failure::Error;
+use logger::Logger;
 use messages::Messages;
 use rusqlite::Connection as SqlConnection;
 use std::collections::HashMap;
 use std::mem;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread::{self, JoinHandle};
 use synac::common::{self, Packet};
@@ -70,13 +73,18 @@ impl Connection {
 
 pub struct Connections {
     pub current_server: Mutex<Option<SocketAddr>>,
+    /// Whether deleted messages are kept as placeholders
+    pub keep_deleted: AtomicBool,
+    pub logger: Mutex<Option<Logger>>,
     pub nick: RwLock<String>,
     pub servers: Arc<Mutex<HashMap<SocketAddr, Connection>>>
 }
 impl Connections {
-    pub fn new(db: &SqlConnection, nick: String) -> Arc<Self> {
+    pub fn new(db: &SqlConnection, nick: String, logger: Option<Logger>) -> Arc<Self> {
         let me = Arc::new(Connections {
             current_server: Mutex::new(None),
+            keep_deleted: AtomicBool::new(false),
+            logger: Mutex::new(logger),
             nick: RwLock::new(nick),
             servers: Arc::new(Mutex::new(HashMap::new()))
         });
@@ -182,13 +190,17 @@ impl Connections {
                     let read = synac.listener.try_read(synac.session.inner_stream())?;
                     if let Some(packet) = read {
                         synac.state.update(&packet);
+                        self.log(synac, &packet);
                         let channel = match packet {
                             Packet::MessageReceive(ref event) => {
                                 synac.messages.add(event.inner.clone());
                                 Some(event.inner.channel)
                             }
-                            Packet::MessageDeleteReceive(ref msg) =>
-                                synac.messages.remove(msg.id),
+                            Packet::MessageDeleteReceive(ref msg) => if self.keep_deleted.load(AtomicOrdering::Relaxed) {
+                                synac.messages.mark_deleted(msg.id)
+                            } else {
+                                synac.messages.remove(msg.id)
+                            },
                             Packet::TypingReceive(ref event) if event.author != synac.user => {
                                 synac.typing.insert(event.author, event.channel);
                                 Some(event.channel)
@@ -202,6 +214,43 @@ impl Connections {
         }
         Ok(())
     }
+    fn log(&self, synac: &Synac, packet: &Packet) {
+        let mut logger = self.logger.lock().unwrap();
+        let logger = match *logger {
+            Some(ref mut logger) => logger,
+            None => return
+        };
+
+        // History requested with MessageList isn't logged,
+        // or it would be appended again on every reconnect.
+        let (msg, timestamp, text) = match *packet {
+            Packet::MessageReceive(ref event) if event.new => {
+                let msg = &event.inner;
+                let text = String::from_utf8_lossy(&msg.text);
+                match msg.timestamp_edit {
+                    Some(edit) => (msg, edit, format!("(edited #{}) {}", msg.id, text)),
+                    None => (msg, msg.timestamp, text.into_owned())
+                }
+            },
+            Packet::MessageDeleteReceive(ref event) => match synac.messages.find(event.id) {
+                Some(msg) => {
+                    let text = String::from_utf8_lossy(&msg.text);
+                    (msg, Utc::now().timestamp(), format!("(deleted #{}) {}", msg.id, text))
+                },
+                None => return
+            },
+            _ => return
+        };
+        let channel = match synac.state.channels.get(&msg.channel) {
+            Some(channel) => channel,
+            None => return
+        };
+        let author = synac.state.users.get(&msg.author).map(|user| &*user.name).unwrap_or("unknown");
+
+        if let Err(err) = logger.log(synac.addr, channel.id, &channel.name, timestamp, author, &text) {
+            eprintln!("error writing log: {}", err);
+        }
+    }
 }
 
 pub fn parse_addr(input: &str) -> Option<SocketAddr> {
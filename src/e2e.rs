@@ -0,0 +1,272 @@
+use failure::Error;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::rsa::{Rsa, PKCS1_OAEP_PADDING};
+use openssl::sha::sha256;
+use openssl::sign::{Signer, Verifier};
+use openssl::symm::{self, Cipher};
+
+pub const KEY_BITS: u32 = 2048;
+
+const TAG_KEY: u8 = 0;
+const TAG_SEALED: u8 = 1;
+
+const SECRET_LEN: usize = 32;
+const IV_LEN: usize = 12;
+const MAC_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+pub const NONCE_LEN: usize = 16;
+const TIMESTAMP_LEN: usize = 8;
+
+#[derive(Debug, Fail)]
+pub enum E2eError {
+    #[fail(display = "not a private message this client understands")]
+    Malformed,
+    #[fail(display = "the message couldn't be decrypted with our key")]
+    Undecryptable,
+    #[fail(display = "the message isn't signed by the key we have for its author")]
+    Forged,
+    #[fail(display = "the message was written for another key of ours")]
+    Misaddressed
+}
+
+/// What a private message carries. The server only ever sees these bytes.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Payload<'a> {
+    /// The sender's public key, in DER, so we can write back
+    Key(&'a [u8]),
+    /// Text only the owner of our private key can read
+    Sealed(&'a [u8])
+}
+
+/// What `open` found in a sealed message.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Opened {
+    pub text: String,
+    /// Random bytes that are different for every message, so a copy sent again can be told apart
+    pub nonce: [u8; NONCE_LEN],
+    /// When the sender wrote it, in seconds since the epoch
+    pub timestamp: i64
+}
+
+pub fn generate() -> Result<PKey, Error> {
+    Ok(PKey::from_rsa(Rsa::generate(KEY_BITS)?)?)
+}
+pub fn public_der(key: &PKey) -> Result<Vec<u8>, Error> {
+    Ok(key.rsa()?.public_key_to_der()?)
+}
+
+/// Formats the SHA-256 of a public key as groups of four hex digits, for comparing by eye.
+pub fn fingerprint(der: &[u8]) -> String {
+    let hash = sha256(der);
+    let mut fingerprint = String::with_capacity(hash.len() * 5 / 2);
+    for (i, byte) in hash.iter().enumerate() {
+        if i > 0 && i % 2 == 0 {
+            fingerprint.push(' ');
+        }
+        fingerprint.push_str(&format!("{:02X}", byte));
+    }
+    fingerprint
+}
+
+pub fn encode_key(der: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(1 + der.len());
+    data.push(TAG_KEY);
+    data.extend_from_slice(der);
+    data
+}
+pub fn decode(data: &[u8]) -> Result<Payload, E2eError> {
+    match data.split_first() {
+        Some((&TAG_KEY, der)) if !der.is_empty() => Ok(Payload::Key(der)),
+        Some((&TAG_SEALED, sealed)) => Ok(Payload::Sealed(sealed)),
+        _ => Err(E2eError::Malformed)
+    }
+}
+
+/// Encrypts `text` for the owner of `recipient`, a public key in DER, and signs it with `sender`.
+/// The text is encrypted with a fresh AES-256-GCM key, which is in turn encrypted with RSA-OAEP.
+/// The signature covers everything after it, so nobody else can write in our name,
+/// not even the server relaying it. It also covers the hash of `recipient`,
+/// so the message can't be passed on to someone else as if it was written for them.
+/// A nonce and `timestamp` go in with the text, for the recipient to spot replays.
+pub fn seal(text: &str, timestamp: i64, recipient: &[u8], sender: &PKey) -> Result<Vec<u8>, Error> {
+    let rsa = Rsa::public_key_from_der(recipient)?;
+
+    let mut secret = [0; SECRET_LEN];
+    let mut iv = [0; IV_LEN];
+    let mut nonce = [0; NONCE_LEN];
+    rand_bytes(&mut secret)?;
+    rand_bytes(&mut iv)?;
+    rand_bytes(&mut nonce)?;
+
+    let mut plaintext = Vec::with_capacity(NONCE_LEN + TIMESTAMP_LEN + text.len());
+    plaintext.extend_from_slice(&nonce);
+    for i in (0..TIMESTAMP_LEN).rev() {
+        plaintext.push((timestamp >> (i * 8)) as u8);
+    }
+    plaintext.extend_from_slice(text.as_bytes());
+
+    let mut wrapped = vec![0; rsa.size()];
+    let len = rsa.public_encrypt(&secret, &mut wrapped, PKCS1_OAEP_PADDING)?;
+    wrapped.truncate(len);
+
+    let mut mac = [0; MAC_LEN];
+    let ciphertext = symm::encrypt_aead(Cipher::aes_256_gcm(), &secret, Some(&iv[..]), &[], &plaintext, &mut mac)?;
+
+    let mut signed = Vec::with_capacity(HASH_LEN + 2 + wrapped.len() + IV_LEN + MAC_LEN + ciphertext.len());
+    signed.extend_from_slice(&sha256(recipient));
+    push_chunk(&mut signed, &wrapped);
+    signed.extend_from_slice(&iv);
+    signed.extend_from_slice(&mac);
+    signed.extend_from_slice(&ciphertext);
+
+    let mut signer = Signer::new(MessageDigest::sha256(), sender)?;
+    signer.update(&signed)?;
+    let signature = signer.sign_to_vec()?;
+
+    let mut data = Vec::with_capacity(3 + signature.len() + signed.len());
+    data.push(TAG_SEALED);
+    push_chunk(&mut data, &signature);
+    data.extend_from_slice(&signed);
+    Ok(data)
+}
+/// Writes a length as two bytes, followed by the data.
+fn push_chunk(data: &mut Vec<u8>, chunk: &[u8]) {
+    data.push((chunk.len() >> 8) as u8);
+    data.push(chunk.len() as u8);
+    data.extend_from_slice(chunk);
+}
+/// Splits off what `push_chunk` wrote.
+fn split_chunk(data: &[u8]) -> Result<(&[u8], &[u8]), E2eError> {
+    if data.len() < 2 {
+        return Err(E2eError::Malformed);
+    }
+    let len = (data[0] as usize) << 8 | data[1] as usize;
+    let data = &data[2..];
+    if data.len() < len {
+        return Err(E2eError::Malformed);
+    }
+    Ok(data.split_at(len))
+}
+/// Checks and decrypts what `seal` wrote, minus the leading tag `decode` already looked at.
+/// `sender` is the public key in DER we have for whoever it claims to be from.
+pub fn open(sealed: &[u8], key: &PKey, sender: &[u8]) -> Result<Opened, E2eError> {
+    let (signature, signed) = split_chunk(sealed)?;
+
+    let verified = Rsa::public_key_from_der(sender)
+        .and_then(PKey::from_rsa)
+        .and_then(|sender| {
+            let mut verifier = Verifier::new(MessageDigest::sha256(), &sender)?;
+            verifier.update(signed)?;
+            verifier.verify(signature)
+        });
+    if verified.ok() != Some(true) {
+        return Err(E2eError::Forged);
+    }
+
+    if signed.len() < HASH_LEN {
+        return Err(E2eError::Malformed);
+    }
+    let (recipient, signed) = signed.split_at(HASH_LEN);
+    let ours = public_der(key).map_err(|_| E2eError::Undecryptable)?;
+    if recipient != sha256(&ours) {
+        return Err(E2eError::Misaddressed);
+    }
+
+    let (wrapped, sealed) = split_chunk(signed)?;
+    if sealed.len() < IV_LEN + MAC_LEN {
+        return Err(E2eError::Malformed);
+    }
+    let (iv, sealed) = sealed.split_at(IV_LEN);
+    let (mac, ciphertext) = sealed.split_at(MAC_LEN);
+
+    let key = key.rsa().map_err(|_| E2eError::Undecryptable)?;
+    let mut secret = vec![0; key.size()];
+    let secret_len = key.private_decrypt(wrapped, &mut secret, PKCS1_OAEP_PADDING)
+        .map_err(|_| E2eError::Undecryptable)?;
+    if secret_len != SECRET_LEN {
+        return Err(E2eError::Undecryptable);
+    }
+    let mut plaintext = symm::decrypt_aead(Cipher::aes_256_gcm(), &secret[..SECRET_LEN], Some(iv), &[], ciphertext, mac)
+        .map_err(|_| E2eError::Undecryptable)?;
+    if plaintext.len() < NONCE_LEN + TIMESTAMP_LEN {
+        return Err(E2eError::Malformed);
+    }
+    let text = plaintext.split_off(NONCE_LEN + TIMESTAMP_LEN);
+
+    let mut nonce = [0; NONCE_LEN];
+    nonce.copy_from_slice(&plaintext[..NONCE_LEN]);
+    let timestamp = plaintext[NONCE_LEN..].iter().fold(0, |timestamp, &byte| timestamp << 8 | byte as i64);
+
+    Ok(Opened {
+        text: String::from_utf8(text).map_err(|_| E2eError::Malformed)?,
+        nonce: nonce,
+        timestamp: timestamp
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    let alice = generate().unwrap();
+    let bob = generate().unwrap();
+    let mallory = generate().unwrap();
+    let alice_der = public_der(&alice).unwrap();
+    let bob_der = public_der(&bob).unwrap();
+
+    let fingerprint = fingerprint(&bob_der);
+    assert_eq!(fingerprint.len(), 32 * 2 + 15);
+    assert!(fingerprint.chars().all(|c| c == ' ' || c.is_digit(16) && !c.is_lowercase()));
+
+    assert_eq!(decode(&encode_key(&bob_der)).unwrap(), Payload::Key(&bob_der));
+    assert!(decode(&[]).is_err());
+    assert!(decode(&[TAG_KEY]).is_err());
+    assert!(decode(&[7, 1, 2]).is_err());
+
+    let mut nonces = Vec::new();
+    for &(text, timestamp) in &[("", 0), ("hi bob", 1_500_000_000), ("ünïcödé 🔐 and a longer line to span more than one AES block", -1)] {
+        let data = seal(text, timestamp, &bob_der, &alice).unwrap();
+        let sealed = match decode(&data).unwrap() {
+            Payload::Sealed(sealed) => sealed,
+            _ => panic!("expected sealed text")
+        };
+        let opened = open(sealed, &bob, &alice_der).unwrap();
+        assert_eq!(opened.text, text);
+        assert_eq!(opened.timestamp, timestamp);
+        assert!(!nonces.contains(&opened.nonce));
+        nonces.push(opened.nonce);
+
+        // Opening the same bytes again gives the same nonce, which is how replays are caught
+        assert_eq!(open(sealed, &bob, &alice_der).unwrap(), opened);
+        assert!(open(sealed, &alice, &alice_der).is_err());
+
+        // Alice can't take what Bob sent her and pass it on to Mallory as his
+        let data = seal(text, timestamp, &alice_der, &bob).unwrap();
+        let passed_on = match decode(&data).unwrap() {
+            Payload::Sealed(sealed) => sealed,
+            _ => panic!("expected sealed text")
+        };
+        match open(passed_on, &mallory, &bob_der) {
+            Err(E2eError::Misaddressed) => (),
+            result => panic!("message for someone else wasn't rejected: {:?}", result)
+        }
+
+        let mut tampered = sealed.to_vec();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(open(&tampered, &bob, &alice_der).is_err());
+
+        // Anyone with Bob's public key can encrypt for him, but can't pass it off as Alice's
+        let data = seal(text, timestamp, &bob_der, &mallory).unwrap();
+        let forged = match decode(&data).unwrap() {
+            Payload::Sealed(sealed) => sealed,
+            _ => panic!("expected sealed text")
+        };
+        match open(forged, &bob, &alice_der) {
+            Err(E2eError::Forged) => (),
+            result => panic!("forged message wasn't rejected: {:?}", result)
+        }
+    }
+    assert!(open(&[0, 5, 1, 2], &bob, &alice_der).is_err());
+}
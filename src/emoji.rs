@@ -0,0 +1,229 @@
+/// Maximum number of recently used emoji to remember.
+pub const RECENT_MAX: usize = 24;
+
+/// Shortcodes and the emoji they stand for, sorted by shortcode.
+/// Names follow the ones GitHub and most chat clients use.
+pub const EMOJI: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("angry", "😠"),
+    ("apple", "🍎"),
+    ("astonished", "😲"),
+    ("baby", "👶"),
+    ("balloon", "🎈"),
+    ("beer", "🍺"),
+    ("bell", "🔔"),
+    ("blush", "😊"),
+    ("bomb", "💣"),
+    ("book", "📖"),
+    ("broken_heart", "💔"),
+    ("bug", "🐛"),
+    ("bulb", "💡"),
+    ("cake", "🍰"),
+    ("calendar", "📆"),
+    ("camera", "📷"),
+    ("cat", "🐱"),
+    ("check", "✔️"),
+    ("clap", "👏"),
+    ("coffee", "☕"),
+    ("computer", "💻"),
+    ("confused", "😕"),
+    ("cookie", "🍪"),
+    ("cool", "🆒"),
+    ("crab", "🦀"),
+    ("cry", "😢"),
+    ("disappointed", "😞"),
+    ("dog", "🐶"),
+    ("eyes", "👀"),
+    ("facepalm", "🤦"),
+    ("fire", "🔥"),
+    ("flushed", "😳"),
+    ("frowning", "😦"),
+    ("gear", "⚙️"),
+    ("ghost", "👻"),
+    ("gift", "🎁"),
+    ("grimacing", "😬"),
+    ("grin", "😁"),
+    ("grinning", "😀"),
+    ("hammer", "🔨"),
+    ("heart", "❤️"),
+    ("heart_eyes", "😍"),
+    ("hourglass", "⌛"),
+    ("hugs", "🤗"),
+    ("innocent", "😇"),
+    ("joy", "😂"),
+    ("key", "🔑"),
+    ("kiss", "😘"),
+    ("laughing", "😆"),
+    ("lock", "🔒"),
+    ("mag", "🔍"),
+    ("muscle", "💪"),
+    ("neutral_face", "😐"),
+    ("no_entry", "⛔"),
+    ("ok", "🆗"),
+    ("ok_hand", "👌"),
+    ("open_mouth", "😮"),
+    ("package", "📦"),
+    ("party", "🥳"),
+    ("pencil", "📝"),
+    ("penguin", "🐧"),
+    ("pensive", "😔"),
+    ("pizza", "🍕"),
+    ("point_down", "👇"),
+    ("point_left", "👈"),
+    ("point_right", "👉"),
+    ("point_up", "👆"),
+    ("poop", "💩"),
+    ("pray", "🙏"),
+    ("question", "❓"),
+    ("rage", "😡"),
+    ("rainbow", "🌈"),
+    ("raised_hands", "🙌"),
+    ("relaxed", "☺️"),
+    ("relieved", "😌"),
+    ("robot", "🤖"),
+    ("rocket", "🚀"),
+    ("rofl", "🤣"),
+    ("rolling_eyes", "🙄"),
+    ("rose", "🌹"),
+    ("scream", "😱"),
+    ("see_no_evil", "🙈"),
+    ("shrug", "🤷"),
+    ("skull", "💀"),
+    ("sleeping", "😴"),
+    ("slightly_smiling_face", "🙂"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("smirk", "😏"),
+    ("sob", "😭"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("star_struck", "🤩"),
+    ("stuck_out_tongue", "😛"),
+    ("sunglasses", "😎"),
+    ("sunny", "☀️"),
+    ("sweat", "😓"),
+    ("sweat_smile", "😅"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("trophy", "🏆"),
+    ("unamused", "😒"),
+    ("upside_down_face", "🙃"),
+    ("v", "✌️"),
+    ("warning", "⚠️"),
+    ("wave", "👋"),
+    ("weary", "😩"),
+    ("wink", "😉"),
+    ("worried", "😟"),
+    ("x", "❌"),
+    ("yum", "😋"),
+    ("zap", "⚡"),
+    ("zipper_mouth_face", "🤐"),
+    ("zzz", "💤")
+];
+
+pub fn get(name: &str) -> Option<&'static str> {
+    EMOJI.binary_search_by_key(&name, |&(name, _)| name)
+        .ok()
+        .map(|i| EMOJI[i].1)
+}
+/// Finds emoji whose shortcode contains `query`, the ones starting with it first.
+pub fn search(query: &str) -> Vec<(&'static str, &'static str)> {
+    let query = query.to_lowercase();
+    let mut results: Vec<_> = EMOJI.iter()
+        .filter(|&&(name, _)| name.starts_with(&*query))
+        .cloned()
+        .collect();
+    results.extend(EMOJI.iter().filter(|&&(name, _)| !name.starts_with(&*query) && name.contains(&*query)));
+    results
+}
+
+/// Returns the start and text of a shortcode being typed right before `cursor`,
+/// like `:smi` in "hello :smi", if it's long enough to be worth completing.
+pub fn shortcode_at(text: &str, cursor: usize) -> Option<(usize, &str)> {
+    let before = &text[..cursor];
+    let start = before.rfind(':')?;
+    let query = &before[start+1..];
+
+    if query.len() < 2 || !query.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '+' || c == '-') {
+        return None;
+    }
+    if before[..start].chars().next_back().map(|c| !c.is_whitespace()).unwrap_or(false) {
+        // Probably a time like 12:30 or a URL
+        return None;
+    }
+    Some((start, query))
+}
+
+/// Replaces every known `:shortcode:` with its emoji.
+/// Text between backticks is left alone, so code isn't mangled.
+pub fn replace(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+
+    for (i, part) in text.split('`').enumerate() {
+        if i > 0 {
+            output.push('`');
+        }
+        if i % 2 == 1 {
+            output.push_str(part);
+            continue;
+        }
+
+        let mut rest = part;
+        while let Some(start) = rest.find(':') {
+            output.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let emoji = rest[1..].find(':').and_then(|end| get(&rest[1..end+1]).map(|emoji| (end, emoji)));
+            match emoji {
+                Some((end, emoji)) => {
+                    output.push_str(emoji);
+                    rest = &rest[end+2..];
+                },
+                None => {
+                    output.push(':');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        output.push_str(rest);
+    }
+
+    output
+}
+
+/// Moves `emoji` to the front of the recently used list.
+pub fn use_recent(recent: &mut Vec<String>, emoji: &str) {
+    recent.retain(|other| other != emoji);
+    recent.insert(0, emoji.to_string());
+    recent.truncate(RECENT_MAX);
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    for pair in EMOJI.windows(2) {
+        assert!(pair[0].0 < pair[1].0, "{} is out of order", pair[1].0);
+    }
+    assert_eq!(get("smile"), Some("😄"));
+    assert_eq!(get("nope"), None);
+
+    assert_eq!(search("smil")[0].0, "smile");
+    assert!(search("smil").iter().any(|&(name, _)| name == "slightly_smiling_face"));
+
+    assert_eq!(shortcode_at("hello :smi", 10), Some((6, "smi")));
+    assert_eq!(shortcode_at(":+1", 3), Some((0, "+1")));
+    assert_eq!(shortcode_at("at 12:30", 8), None);
+    assert_eq!(shortcode_at("hello :s", 8), None);
+
+    assert_eq!(replace("hi :wave: :nope: 12:30"), "hi 👋 :nope: 12:30");
+    assert_eq!(replace(":+1::tada:"), "👍🎉");
+    assert_eq!(replace("`:wave:` :wave:"), "`:wave:` 👋");
+
+    let mut recent = vec![String::from("a"), String::from("b")];
+    use_recent(&mut recent, "b");
+    assert_eq!(recent, &["b", "a"]);
+}
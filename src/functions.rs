@@ -4,6 +4,9 @@ use ::*;
 // otherwise complains about publishing a private type.
 // It's not like you can `extern crate` a program, can you?
 
+/// Lines kept in the bot console.
+const BOT_CONSOLE_MAX: usize = 500;
+
 pub(crate) fn add_class<T: WidgetExt>(widget: &T, class: &str) {
     widget.get_style_context().and_then(|context| {
         context.add_class(class);
@@ -21,6 +24,895 @@ pub(crate) fn alert(window: &Window, kind: MessageType, message: &str) {
     dialog.connect_response(|dialog, _| dialog.destroy());
     dialog.show_all();
 }
+pub(crate) fn get_data(db: &SqlConnection, key: &str) -> Option<String> {
+    let mut stmt = db.prepare_cached("SELECT value FROM data WHERE key = ?").unwrap();
+    let mut rows = stmt.query(&[&key]).unwrap();
+
+    rows.next().map(|row| row.unwrap().get(0))
+}
+pub(crate) fn set_data(db: &SqlConnection, key: &str, value: &str) {
+    db.execute("REPLACE INTO data (key, value) VALUES (?, ?)", &[&key, &value]).unwrap();
+}
+pub(crate) fn load_logger(db: &SqlConnection, basedirs: &BaseDirectories) -> Option<Logger> {
+    if get_data(db, "log_enabled").map(|value| value == "1").unwrap_or(false) {
+        let format = get_data(db, "log_format").unwrap_or_else(|| String::from(logger::DEFAULT_FORMAT));
+        Some(Logger::new(basedirs.get_data_home().join("logs"), format))
+    } else {
+        None
+    }
+}
+pub(crate) fn load_time_format(db: &SqlConnection) -> TimeFormat {
+    let mut format = TimeFormat::default();
+    if let Some(clock) = get_data(db, "time_clock") {
+        format.clock24 = clock == "24";
+    }
+    if let Some(dates) = get_data(db, "time_dates").and_then(|dates| Dates::from_id(&dates)) {
+        format.dates = dates;
+    }
+    if let Some(custom) = get_data(db, "time_custom") {
+        if messages::valid_strftime(&custom) {
+            format.custom = custom;
+        }
+    }
+    format
+}
+pub(crate) fn load_deleted_prefs(app: &Rc<App>) {
+    let keep = get_data(&app.db, "deleted_keep").map(|value| value == "1").unwrap_or(false);
+    let reveal = get_data(&app.db, "deleted_reveal").map(|value| value == "1").unwrap_or(false);
+
+    app.connections.keep_deleted.store(keep, AtomicOrdering::Relaxed);
+    app.reveal_deleted.set(keep && reveal);
+    if !keep {
+        app.connections.foreach(|synac| synac.messages.purge_deleted());
+    }
+}
+pub(crate) fn load_emoji_recent(db: &SqlConnection) -> Vec<String> {
+    get_data(db, "emoji_recent")
+        .map(|recent| recent.split_whitespace().map(String::from).collect())
+        .unwrap_or_default()
+}
+pub(crate) fn remember_emoji(app: &Rc<App>, emoji: &str) {
+    let mut recent = app.emoji_recent.borrow_mut();
+    emoji::use_recent(&mut recent, emoji);
+    set_data(&app.db, "emoji_recent", &recent.join(" "));
+}
+pub(crate) fn composer_text(composer: &TextView) -> String {
+    let buffer = composer.get_buffer().unwrap();
+    let (start, end) = buffer.get_bounds();
+    buffer.get_text(&start, &end, false).unwrap_or_default()
+}
+pub(crate) fn set_composer_text(app: &Rc<App>, text: &str) {
+    // Replacing the text isn't typing, and this may run while a connection is locked
+    app.composer_silent.set(true);
+    app.message_composer.get_buffer().unwrap().set_text(text);
+    app.composer_silent.set(false);
+}
+/// Returns the shortcode being typed in the composer,
+/// along with its start and end as character offsets.
+pub(crate) fn emoji_query(input: &TextView) -> Option<(i32, i32, String)> {
+    let buffer = input.get_buffer()?;
+    let cursor = buffer.get_iter_at_mark(&buffer.get_insert()?);
+    let before = buffer.get_text(&buffer.get_start_iter(), &cursor, false).unwrap_or_default();
+
+    let (start, query) = emoji::shortcode_at(&before, before.len())?;
+    Some((before[..start].chars().count() as i32, cursor.get_offset(), query.to_string()))
+}
+/// Replaces the characters from `start` to `end`, leaving the cursor after `text`.
+pub(crate) fn replace_composer_range(input: &TextView, start: i32, end: i32, text: &str) {
+    let buffer = input.get_buffer().unwrap();
+    let mut start = buffer.get_iter_at_offset(start);
+    let mut end = buffer.get_iter_at_offset(end);
+    buffer.delete(&mut start, &mut end);
+    buffer.insert(&mut start, text);
+    buffer.place_cursor(&start);
+}
+pub(crate) fn complete_emoji(app: &Rc<App>, input: &TextView, start: i32, end: i32, emoji: &str) {
+    replace_composer_range(input, start, end, emoji);
+    remember_emoji(app, emoji);
+}
+/// Completes the nick, channel, bot or command before the cursor, or
+/// moves on to the next candidate if the last key was Tab as well.
+/// Returns false if there was nothing to complete.
+pub(crate) fn complete_word(app: &Rc<App>) -> bool {
+    let cycle = app.completion.cycle.borrow_mut().take();
+    let mut cycle = match cycle.or_else(|| start_completion(app)) {
+        Some(cycle) => cycle,
+        None => return false
+    };
+
+    let (start, end, text) = cycle.advance();
+    replace_composer_range(&app.message_composer, start as i32, end as i32, &text);
+
+    if cycle.candidates().len() > 1 {
+        render_completion(app, &cycle);
+        *app.completion.cycle.borrow_mut() = Some(cycle);
+    } else {
+        app.completion.popover.hide();
+    }
+    true
+}
+fn start_completion(app: &Rc<App>) -> Option<completion::Cycle> {
+    let buffer = app.message_composer.get_buffer()?;
+    let cursor = buffer.get_iter_at_mark(&buffer.get_insert()?);
+    let before = buffer.get_text(&buffer.get_start_iter(), &cursor, false).unwrap_or_default();
+    let (start, kind, prefix) = completion::word_at(&before, before.len())?;
+
+    let mut candidates = Vec::new();
+    let aliases = load_aliases(&app.db);
+    let aliases = aliases.iter().map(|&(ref name, _)| &**name);
+    if kind == completion::Kind::Command {
+        candidates = completion::candidates(prefix, commands::COMMANDS.iter().map(|command| command.name).chain(aliases));
+    } else if let Some(addr) = *app.connections.current_server.lock().unwrap() {
+        app.connections.execute(addr, |result| {
+            if let Ok(synac) = result {
+                let users = synac.state.users.values();
+                candidates = match kind {
+                    completion::Kind::Nick =>
+                        completion::candidates(prefix, users.map(|user| &*user.name)),
+                    completion::Kind::Bot =>
+                        completion::candidates(prefix, users.filter(|user| user.bot).map(|user| &*user.name).chain(aliases)),
+                    completion::Kind::Channel =>
+                        completion::candidates(prefix, synac.state.channels.values().map(|channel| &*channel.name)),
+                    completion::Kind::Command => unreachable!()
+                };
+            }
+        });
+    }
+    if candidates.is_empty() {
+        return None;
+    }
+    Some(completion::Cycle::new(
+        before[..start].chars().count(),
+        before[start..].chars().count(),
+        kind,
+        candidates
+    ))
+}
+pub(crate) fn reset_completion(app: &Rc<App>) {
+    if app.completion.cycle.borrow_mut().take().is_some() {
+        app.completion.popover.hide();
+    }
+}
+fn render_completion(app: &Rc<App>, cycle: &completion::Cycle) {
+    let list = &app.completion.list;
+    for child in list.get_children() {
+        list.remove(&child);
+    }
+    for (i, name) in cycle.candidates().iter().enumerate() {
+        let button = Button::new_with_label(name);
+        button.set_relief(ReliefStyle::None);
+
+        let app_clone = Rc::clone(app);
+        button.connect_clicked(move |_| {
+            let picked = app_clone.completion.cycle.borrow_mut().take().map(|mut cycle| cycle.pick(i));
+            if let Some((start, end, text)) = picked {
+                replace_composer_range(&app_clone.message_composer, start as i32, end as i32, &text);
+            }
+            app_clone.completion.popover.hide();
+            app_clone.message_composer.grab_focus();
+        });
+        list.add(&button);
+    }
+    list.show_all();
+    app.completion.popover.show();
+}
+pub(crate) fn render_emoji_completion(app: &Rc<App>, input: &TextView, popover: &Popover, list: &GtkBox) {
+    for child in list.get_children() {
+        list.remove(&child);
+    }
+    let matches = emoji_query(input).map(|(_, _, query)| emoji::search(&query)).unwrap_or_default();
+    if matches.is_empty() {
+        popover.hide();
+        return;
+    }
+
+    for &(name, emoji) in matches.iter().take(8) {
+        let button = Button::new_with_label(&format!("{} :{}:", emoji, name));
+        button.set_relief(ReliefStyle::None);
+
+        let app_clone = Rc::clone(app);
+        let input = input.clone();
+        let popover_clone = popover.clone();
+        button.connect_clicked(move |_| {
+            if let Some((start, end, _)) = emoji_query(&input) {
+                complete_emoji(&app_clone, &input, start, end, emoji);
+            }
+            popover_clone.hide();
+            input.grab_focus();
+        });
+        list.add(&button);
+    }
+    list.show_all();
+    popover.show();
+}
+pub(crate) fn render_emoji_picker(app: &Rc<App>, input: &TextView) {
+    let picker = &app.emoji_picker;
+    let query = picker.search.get_text().unwrap_or_default();
+
+    for flowbox in &[&picker.recent, &picker.all] {
+        for child in flowbox.get_children() {
+            flowbox.remove(&child);
+        }
+    }
+
+    let recent = app.emoji_recent.borrow().clone();
+    let show_recent = query.is_empty() && !recent.is_empty();
+    picker.recent_label.set_visible(show_recent);
+    picker.recent.set_visible(show_recent);
+    if show_recent {
+        for emoji in &recent {
+            let name = emoji::EMOJI.iter().find(|&&(_, other)| emoji == other).map(|&(name, _)| name);
+            picker.recent.add(&emoji_button(app, input, emoji, name));
+        }
+        picker.recent.show_all();
+    }
+    for (name, emoji) in emoji::search(query.trim()) {
+        picker.all.add(&emoji_button(app, input, emoji, Some(name)));
+    }
+    picker.all.show_all();
+}
+fn emoji_button(app: &Rc<App>, input: &TextView, emoji: &str, name: Option<&str>) -> Button {
+    let button = Button::new_with_label(emoji);
+    button.set_relief(ReliefStyle::None);
+    if let Some(name) = name {
+        button.set_tooltip_text(&*format!(":{}:", name));
+    }
+
+    let app_clone = Rc::clone(app);
+    let input = input.clone();
+    let emoji = emoji.to_string();
+    button.connect_clicked(move |_| {
+        if let Some(buffer) = input.get_buffer() {
+            buffer.insert_at_cursor(&emoji);
+        }
+        app_clone.emoji_picker.popover.hide();
+        remember_emoji(&app_clone, &emoji);
+        input.grab_focus();
+    });
+    button
+}
+/// Sends whatever is in the composer, or runs it if it's a command.
+pub(crate) fn send_message(app: &Rc<App>) {
+    let text = composer_text(&app.message_composer);
+    if text.trim().is_empty() {
+        return;
+    }
+    remember_sent(app, &text);
+    hide_command_output(app);
+
+    // Cleared first, since commands like /join switch to another channel's draft
+    set_composer_text(app, "");
+
+    // Aliases are only expanded once, so they can't loop
+    let result = match expand_alias(app, &text) {
+        Some(expanded) => expanded.and_then(|line| run_line(app, &line)),
+        None => run_line(app, &text)
+    };
+    match result {
+        Ok(Some(output)) => show_command_output(app, &output, false),
+        Ok(None) => (),
+        Err(err) => {
+            // Give the text back so it can be fixed
+            set_composer_text(app, &text);
+            show_command_output(app, &err, true);
+        }
+    }
+    // Drops the stored draft now that it's sent, or keeps the text given back
+    save_draft(app);
+    app.message_composer.grab_focus();
+}
+/// Runs a command, or sends a bot command or message, depending on how `line` starts.
+fn run_line(app: &Rc<App>, line: &str) -> Result<Option<String>, String> {
+    if let Some(invocation) = commands::parse(line) {
+        invocation
+            .map_err(|err| command_error(&err))
+            .and_then(|invocation| run_command(app, &invocation))
+    } else if line.starts_with('!') {
+        run_bot_command(app, &line[1..]).map(|_| None)
+    } else if let Some((addr, user)) = *app.direct.borrow() {
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+        send_private(app, addr, user, commands::unescape(line)).map(|_| None)
+    } else {
+        let current = *app.draft_channel.borrow();
+        if let (Some((addr, channel)), false) = (current, line.trim().is_empty()) {
+            send_text(app, addr, channel, commands::unescape(line));
+        }
+        Ok(None)
+    }
+}
+/// Sends a message, reconnecting if the connection turns out to be gone.
+pub(crate) fn send_text(app: &Rc<App>, addr: SocketAddr, channel: usize, text: &str) {
+    app.connections.execute(addr, |result| {
+        if result.is_err() {
+            return;
+        }
+        let synac = result.unwrap();
+        let result = synac.session.send(&Packet::MessageCreate(common::MessageCreate {
+            channel: channel,
+            text: emoji::replace(text).into_bytes()
+        }));
+        if let Err(err) = result {
+            if let Ok(io_err) = err.downcast::<IoError>() {
+                if io_err.kind() != IoErrorKind::BrokenPipe {
+                    return;
+                }
+            }
+
+            let mut stmt = app.db.prepare_cached("SELECT hash, token FROM servers WHERE ip = ?").unwrap();
+            let mut rows = stmt.query(&[&addr.to_string()]).unwrap();
+
+            if let Some(row) = rows.next() {
+                let row = row.unwrap();
+
+                let hash = row.get(0);
+                let token = row.get(1);
+
+                connect(app, addr, hash, token);
+            }
+        }
+    });
+}
+/// Runs `!<bot> <command> [args...]`.
+fn run_bot_command(app: &Rc<App>, text: &str) -> Result<(), String> {
+    // The ! isn't part of `text`, but is part of what the user typed
+    let mut args = parser::parse(text).map_err(|err| parse_error(&err.shifted(1)))?;
+    if args.len() < 2 {
+        return Err(tr("!<user> <command> [args...]"));
+    }
+    let recipient = args.remove(0);
+    invoke_bot(app, &recipient, args)
+}
+/// Sends a command to a bot on the current server, and shows it in the bot console.
+pub(crate) fn invoke_bot(app: &Rc<App>, name: &str, args: Vec<String>) -> Result<(), String> {
+    let addr = current_server(app)?;
+    let shown = parser::join(&args);
+
+    let mut bot = None;
+    app.connections.execute(addr, |result| {
+        if let Ok(synac) = result {
+            let user_id = synac.state.users.values()
+                .find(|user| user.bot && user.name == name)
+                .map(|user| user.id);
+
+            if let Some(user_id) = user_id {
+                bot = Some(user_id);
+                if let Err(err) = synac.session.send(&Packet::Command(common::Command {
+                    args: args,
+                    recipient: user_id
+                })) {
+                    eprintln!("failed to send packet: {}", err);
+                }
+            }
+        }
+    });
+    let bot = bot.ok_or_else(|| tr("No bot with that id."))?;
+
+    app.bot_console.invoked.borrow_mut().insert((addr, bot));
+    log_bot_console(app, addr, &format!("!{} {}", name, shown), None);
+    Ok(())
+}
+/// Adds a line to a server's bot console. Without an author, it's something we sent.
+pub(crate) fn log_bot_console(app: &Rc<App>, addr: SocketAddr, text: &str, author: Option<&str>) {
+    {
+        let mut lines = app.bot_console.lines.borrow_mut();
+        let lines = lines.entry(addr).or_insert_with(Vec::new);
+        lines.push((text.to_string(), author.map(String::from)));
+        if lines.len() > BOT_CONSOLE_MAX {
+            let extra = lines.len() - BOT_CONSOLE_MAX;
+            lines.drain(..extra);
+        }
+    }
+    if app.bot_console.shown.get() == Some(addr) {
+        add_bot_console_line(app, text, author);
+    }
+}
+fn add_bot_console_line(app: &Rc<App>, text: &str, author: Option<&str>) {
+    let console = &app.bot_console;
+
+    let label = Label::new(None);
+    label.set_line_wrap(true);
+    label.set_line_wrap_mode(WrapMode::WordChar);
+    label.set_selectable(true);
+    label.set_xalign(0.0);
+    match author {
+        Some(author) => {
+            let safe = messages::sanitize(text.as_bytes());
+            let mut output = String::new();
+            output.push_str("<b>");
+            markup::escape(&mut output, &messages::sanitize(author.as_bytes()));
+            output.push_str(":</b> ");
+            output.push_str(&markup::render(&safe, app.theme));
+
+            label.set_text(&safe); // In case set_markup fails.
+            label.set_markup(&output);
+
+            let app_clone = Rc::clone(app);
+            label.connect_activate_link(move |_, uri| {
+                open_link(&app_clone, uri);
+                Inhibit(true)
+            });
+        },
+        None => {
+            label.set_text(text);
+            add_class(&label, "time");
+        }
+    }
+    console.log.add(&label);
+
+    let rows = console.log.get_children();
+    if rows.len() > BOT_CONSOLE_MAX {
+        for row in &rows[..rows.len() - BOT_CONSOLE_MAX] {
+            console.log.remove(row);
+        }
+    }
+    console.log.show_all();
+
+    // Wait until the line is rendered
+
+    let app = Rc::clone(app);
+    gtk::idle_add(move || {
+        if let Some(vadjustment) = app.bot_console.log_scroll.get_vadjustment() {
+            vadjustment.set_value(vadjustment.get_upper());
+        }
+        Continue(false)
+    });
+}
+/// Fills the bot console's list with the bots on the current server,
+/// and shows its log if the server changed.
+pub(crate) fn render_bots(app: &Rc<App>, synac: Option<&mut Synac>) {
+    let addr = synac.as_ref().map(|synac| synac.addr);
+    if app.bot_console.shown.get() != addr {
+        app.bot_console.shown.set(addr);
+        for row in app.bot_console.log.get_children() {
+            app.bot_console.log.remove(&row);
+        }
+        if let Some(lines) = addr.and_then(|addr| app.bot_console.lines.borrow().get(&addr).cloned()) {
+            for &(ref text, ref author) in &lines {
+                add_bot_console_line(app, text, author.as_ref().map(|author| &**author));
+            }
+        }
+    }
+
+    let combo = &app.bot_console.bots;
+    let selected = combo.get_active_id();
+    combo.remove_all();
+
+    if let Some(synac) = synac {
+        let mut bots: Vec<_> = synac.state.users.values()
+            .filter(|user| user.bot)
+            .map(|user| &*user.name)
+            .collect();
+        bots.sort();
+        for &bot in &bots {
+            combo.append(Some(bot), bot);
+        }
+        let selected = match selected {
+            Some(ref selected) if bots.contains(&&**selected) => Some(&**selected),
+            _ => bots.first().cloned()
+        };
+        if let Some(selected) = selected {
+            combo.set_active_id(Some(selected));
+        }
+    }
+}
+/// Shows how the bot console's input will be split into arguments.
+pub(crate) fn render_bot_args(app: &Rc<App>) {
+    let console = &app.bot_console;
+    if let Some(context) = console.args.get_style_context() {
+        context.remove_class("error");
+    }
+    let args = match parser::parse(&console.input.get_text().unwrap_or_default()) {
+        Ok(args) => args,
+        Err(err) => {
+            console.args.set_text(&parse_error(&err));
+            add_class(&console.args, "error");
+            return;
+        }
+    };
+    if args.is_empty() {
+        console.args.set_text(&tr("Type a command, followed by its arguments. Quote arguments containing spaces."));
+        return;
+    }
+    let shown = args.iter().map(|arg| parser::quote(arg)).collect::<Vec<_>>().join("  ");
+    console.args.set_text(&fill(&trn("{} argument: {}", "{} arguments: {}", args.len() as u64), &[&args.len().to_string(), &shown]));
+}
+/// Sends what's typed in the bot console to the selected bot.
+pub(crate) fn send_bot_console(app: &Rc<App>) {
+    let console = &app.bot_console;
+    let text = console.input.get_text().unwrap_or_default();
+    let args = match parser::parse(&text) {
+        Ok(args) => args,
+        Err(err) => {
+            console.args.set_text(&parse_error(&err));
+            add_class(&console.args, "error");
+            return;
+        }
+    };
+    if args.is_empty() {
+        return;
+    }
+    let bot = match console.bots.get_active_id() {
+        Some(bot) => bot,
+        None => {
+            console.args.set_text(&tr("There are no bots on this server."));
+            add_class(&console.args, "error");
+            return;
+        }
+    };
+    match invoke_bot(app, &bot, args) {
+        Ok(()) => {
+            console.history.borrow_mut().entry(bot).or_insert_with(History::default).push(&text);
+            console.input.set_text("");
+        },
+        Err(err) => {
+            console.args.set_text(&err);
+            add_class(&console.args, "error");
+        }
+    }
+}
+/// Steps through what was sent to the selected bot.
+pub(crate) fn recall_bot_history(app: &Rc<App>, older: bool) {
+    let console = &app.bot_console;
+    let bot = match console.bots.get_active_id() {
+        Some(bot) => bot,
+        None => return
+    };
+    let current = console.input.get_text().unwrap_or_default();
+    let text = {
+        let mut history = console.history.borrow_mut();
+        let history = history.entry(bot).or_insert_with(History::default);
+        let text = if older { history.older(&current) } else { history.newer() };
+        text.map(String::from)
+    };
+    if let Some(text) = text {
+        console.input.set_text(&text);
+        console.input.set_position(-1);
+    }
+}
+fn current_server(app: &Rc<App>) -> Result<SocketAddr, String> {
+    app.connections.current_server.lock().unwrap()
+        .ok_or_else(|| tr("You're not connected to a server."))
+}
+/// The server and channel the composer belongs to.
+fn current_channel(app: &Rc<App>) -> Result<(SocketAddr, usize), String> {
+    app.draft_channel.borrow()
+        .ok_or_else(|| tr("You're not in a channel."))
+}
+fn command_error(err: &CommandError) -> String {
+    match *err {
+        CommandError::Unknown(ref name) => fill(
+            &tr("Unknown command /{}. Type /help to list them, or start with // to send a message beginning with /."),
+            &[name]
+        ),
+        CommandError::Usage(name, usage) => fill(&tr("Usage: /{} {}"), &[name, usage]),
+        CommandError::Parse(ref err) => parse_error(err)
+    }
+}
+fn parse_error(err: &ParseError) -> String {
+    match *err {
+        ParseError::UnterminatedQuote(column) => fill(&tr("Unterminated quote at column {}."), &[&column.to_string()])
+    }
+}
+fn command_help(command: &commands::Command) -> String {
+    let mut help = format!("/{}", command.name);
+    if !command.usage.is_empty() {
+        help.push(' ');
+        help.push_str(command.usage);
+    }
+    help.push_str(" - ");
+    help.push_str(&tr(command.description));
+    help
+}
+/// Runs a client command, returning anything it wants to show above the composer.
+fn run_command(app: &Rc<App>, invocation: &commands::Invocation) -> Result<Option<String>, String> {
+    let args = &invocation.args;
+    match invocation.command.name {
+        "clear" => {
+            let (addr, channel) = current_channel(app)?;
+            app.connections.execute(addr, |result| {
+                if let Ok(synac) = result {
+                    synac.messages.clear(channel);
+                    render_messages(app, Some(synac));
+                }
+            });
+            Ok(None)
+        },
+        "connect" => {
+            let addr = connections::parse_addr(&args[0])
+                .ok_or_else(|| fill(&tr("Failed to resolve server {}"), &[&args[0]]))?;
+            match find_server(&app.db, addr) {
+                Some((_, ref hash, _)) if *hash != args[1] => return Err(fill(
+                    &tr("{} is already added, with a different hash."),
+                    &[&args[0]]
+                )),
+                Some(_) => (),
+                None => {
+                    app.db.execute(
+                        "INSERT INTO servers (name, ip, hash) VALUES (?, ?, ?)",
+                        &[&args[0], &addr.to_string(), &args[1]]
+                    ).unwrap();
+                    render_servers(app);
+                }
+            }
+            let (name, hash, token) = find_server(&app.db, addr).unwrap();
+            select_server(app, addr, &name, &hash, &token);
+            Ok(None)
+        },
+        "help" => match args.first() {
+            Some(name) => commands::find(name)
+                .map(|command| Some(command_help(command)))
+                .ok_or_else(|| command_error(&CommandError::Unknown(name.trim_left_matches('/').to_string()))),
+            None => {
+                let help: Vec<_> = commands::COMMANDS.iter().map(command_help).collect();
+                Ok(Some(help.join("\n")))
+            }
+        },
+        "join" => {
+            let addr = current_server(app)?;
+            let name = args[0].trim_left_matches('#');
+            let mut found = false;
+            app.connections.execute(addr, |result| {
+                if let Ok(synac) = result {
+                    let channel = {
+                        let channels: Vec<_> = synac.state.channels.values().collect();
+                        let id = channels.iter().find(|channel| channel.name == name)
+                            .or_else(|| channels.iter().find(|channel| channel.name.to_lowercase() == name.to_lowercase()))
+                            .map(|channel| channel.id);
+                        id
+                    };
+                    if let Some(channel) = channel {
+                        found = true;
+                        select_channel(app, synac, channel);
+                    }
+                }
+            });
+            if found {
+                Ok(None)
+            } else {
+                Err(fill(&tr("There's no channel called #{} here."), &[name]))
+            }
+        },
+        "me" => {
+            let (addr, channel) = current_channel(app)?;
+            let nick = app.connections.nick.read().unwrap().clone();
+            send_text(app, addr, channel, &format!("*{} {}*", nick, invocation.text));
+            Ok(None)
+        },
+        "mute" | "unmute" => {
+            let (addr, channel) = current_channel(app)?;
+            let mute = invocation.command.name == "mute";
+
+            let mut stmt = app.db.prepare_cached(
+                "SELECT COUNT(*) FROM muted WHERE channel = ? AND server = ?"
+            ).unwrap();
+            let count: i64 = stmt.query_row(
+                &[&(channel as i64), &addr.to_string()],
+                |row| row.get(0)
+            ).unwrap();
+
+            if mute == (count > 0) {
+                return Err(tr(if mute {
+                    "This channel is already muted."
+                } else {
+                    "This channel isn't muted."
+                }));
+            }
+            app.db.execute(
+                if mute {
+                    "INSERT INTO muted (channel, server) VALUES (?, ?)"
+                } else {
+                    "DELETE FROM muted WHERE channel = ? AND server = ?"
+                },
+                &[&(channel as i64), &addr.to_string()]
+            ).unwrap();
+            Ok(None)
+        },
+        "nick" => set_nick(app, &args[0]).map(|_| None),
+        "open" => {
+            let link = Permalink::parse(&args[0])
+                .map_err(|err| fill(&tr("Invalid link: {}"), &[&err.to_string()]))?;
+            open_permalink(app, &link);
+            Ok(None)
+        },
+        "topic" => {
+            let (addr, channel) = current_channel(app)?;
+            if invocation.text.is_empty() {
+                app.db.execute(
+                    "DELETE FROM topics WHERE server = ? AND channel = ?",
+                    &[&addr.to_string(), &(channel as i64)]
+                ).unwrap();
+            } else {
+                app.db.execute(
+                    "REPLACE INTO topics (server, channel, text) VALUES (?, ?, ?)",
+                    &[&addr.to_string(), &(channel as i64), &invocation.text]
+                ).unwrap();
+            }
+            render_topic(app, Some((addr, channel)));
+            Ok(None)
+        },
+        _ => unreachable!()
+    }
+}
+/// Shows the result of a command above the composer, until something else is typed.
+pub(crate) fn show_command_output(app: &Rc<App>, output: &str, error: bool) {
+    app.command_output.set_text(output);
+    if error {
+        add_class(&app.command_output, "error");
+    } else if let Some(context) = app.command_output.get_style_context() {
+        context.remove_class("error");
+    }
+    app.command_output_revealer.set_reveal_child(true);
+}
+pub(crate) fn hide_command_output(app: &Rc<App>) {
+    app.command_output_revealer.set_reveal_child(false);
+}
+/// Shows the note set with /topic for a channel, if there is one.
+pub(crate) fn render_topic(app: &Rc<App>, channel: Option<(SocketAddr, usize)>) {
+    let topic = channel.and_then(|(addr, channel)| {
+        let mut stmt = app.db.prepare_cached("SELECT text FROM topics WHERE server = ? AND channel = ?").unwrap();
+        let mut rows = stmt.query(&[&addr.to_string(), &(channel as i64)]).unwrap();
+
+        rows.next().map(|row| row.unwrap().get::<_, String>(0))
+    });
+    app.channel_topic.set_text(&topic.unwrap_or_default());
+}
+/// Changes our name on all servers. Returns the first error, if any.
+pub(crate) fn set_nick(app: &Rc<App>, name: &str) -> Result<(), String> {
+    if name.is_empty() || name == *app.connections.nick.read().unwrap() {
+        return Ok(());
+    }
+    app.user_name.set_text(name);
+
+    let mut error = Ok(());
+    app.connections.foreach(|synac| {
+        let result = synac.session.send(&Packet::LoginUpdate(common::LoginUpdate {
+            name: Some(name.to_string()),
+            password_current: None,
+            password_new: None,
+            reset_token: false
+        }));
+        if let Err(err) = result {
+            if error.is_ok() {
+                error = Err(fill(&tr("failed to update server {}: {}"), &[&synac.addr.to_string(), &err.to_string()]));
+            }
+        }
+    });
+
+    app.db.execute("REPLACE INTO data (key, value) VALUES ('nick', ?)", &[&name]).unwrap();
+
+    *app.connections.nick.write().unwrap() = name.to_string();
+    error
+}
+/// Stores what's in the composer as the draft of the channel it was typed in.
+pub(crate) fn save_draft(app: &Rc<App>) {
+    let (addr, channel) = match *app.draft_channel.borrow() {
+        Some(key) => key,
+        None => return
+    };
+    let text = composer_text(&app.message_composer);
+    if text.trim().is_empty() {
+        app.db.execute(
+            "DELETE FROM drafts WHERE server = ? AND channel = ?",
+            &[&addr.to_string(), &(channel as i64)]
+        ).unwrap();
+    } else {
+        app.db.execute(
+            "REPLACE INTO drafts (server, channel, text) VALUES (?, ?, ?)",
+            &[&addr.to_string(), &(channel as i64), &text]
+        ).unwrap();
+    }
+}
+/// Saves the current draft and fills the composer with the one for `target`,
+/// so text never follows you into a channel it wasn't written for.
+pub(crate) fn switch_draft(app: &Rc<App>, target: Option<(SocketAddr, usize)>) {
+    if *app.draft_channel.borrow() == target {
+        return;
+    }
+    save_draft(app);
+
+    let text = target.and_then(|(addr, channel)| {
+        let mut stmt = app.db.prepare_cached("SELECT text FROM drafts WHERE server = ? AND channel = ?").unwrap();
+        let mut rows = stmt.query(&[&addr.to_string(), &(channel as i64)]).unwrap();
+
+        rows.next().map(|row| row.unwrap().get::<_, String>(0))
+    });
+    *app.draft_channel.borrow_mut() = target;
+    set_composer_text(app, &text.unwrap_or_default());
+}
+pub(crate) fn load_history(db: &SqlConnection, addr: SocketAddr, channel: usize) -> History {
+    let mut stmt = db.prepare_cached(
+        "SELECT text FROM history WHERE server = ? AND channel = ? ORDER BY rowid DESC LIMIT ?"
+    ).unwrap();
+    let mut rows = stmt.query(&[&addr.to_string(), &(channel as i64), &(history::MAX as i64)]).unwrap();
+
+    let mut entries = Vec::new();
+    while let Some(row) = rows.next() {
+        entries.push(row.unwrap().get::<_, String>(0));
+    }
+    entries.reverse();
+    History::new(entries)
+}
+/// Adds sent text to the history of the channel it was typed in.
+pub(crate) fn remember_sent(app: &Rc<App>, text: &str) {
+    let (addr, channel) = match *app.draft_channel.borrow() {
+        Some(key) => key,
+        None => return
+    };
+    if !app.history.borrow_mut().push(text) {
+        return;
+    }
+    let addr = addr.to_string();
+    let channel = channel as i64;
+    app.db.execute(
+        "INSERT INTO history (server, channel, text) VALUES (?, ?, ?)",
+        &[&addr, &channel, &text]
+    ).unwrap();
+    app.db.execute(
+        "DELETE FROM history WHERE server = ? AND channel = ? AND rowid NOT IN (
+            SELECT rowid FROM history WHERE server = ? AND channel = ? ORDER BY rowid DESC LIMIT ?
+        )",
+        &[&addr, &channel, &addr, &channel, &(history::MAX as i64)]
+    ).unwrap();
+}
+/// Replaces the composer's text with an older or newer sent message.
+pub(crate) fn recall_history(app: &Rc<App>, older: bool) {
+    let current = composer_text(&app.message_composer);
+    let text = {
+        let mut history = app.history.borrow_mut();
+        let text = if older { history.older(&current) } else { history.newer() };
+        text.map(String::from)
+    };
+    if let Some(text) = text {
+        set_composer_text(app, &text);
+    }
+}
+pub(crate) fn start_history_search(app: &Rc<App>) {
+    let search = &app.history_search;
+    *search.stash.borrow_mut() = composer_text(&app.message_composer);
+    search.found.set(None);
+    search.entry.set_text("");
+    search.revealer.set_reveal_child(true);
+    search.entry.grab_focus();
+}
+/// Shows the newest sent message matching the search, or the next older one.
+pub(crate) fn search_history(app: &Rc<App>, older: bool) {
+    let search = &app.history_search;
+    let query = search.entry.get_text().unwrap_or_default();
+    let before = if older { search.found.get() } else { None };
+
+    let found = {
+        let history = app.history.borrow();
+        history.search(&query, before).map(|i| (i, history.get(i).unwrap_or("").to_string()))
+    };
+    if let Some((i, ref text)) = found {
+        search.found.set(Some(i));
+        set_composer_text(app, text);
+    }
+    if found.is_none() && !query.is_empty() {
+        add_class(&search.entry, "error");
+    } else if let Some(context) = search.entry.get_style_context() {
+        context.remove_class("error");
+    }
+}
+/// Closes the search, keeping the match in the composer if `accept` is set.
+pub(crate) fn end_history_search(app: &Rc<App>, accept: bool) {
+    let search = &app.history_search;
+    if !accept {
+        let stash = search.stash.borrow().clone();
+        set_composer_text(app, &stash);
+    }
+    app.history.borrow_mut().reset();
+    search.revealer.set_reveal_child(false);
+    app.message_composer.grab_focus();
+}
+pub(crate) fn render_preview(app: &Rc<App>, preview: &Label) {
+    let text = emoji::replace(&composer_text(&app.message_composer));
+    let safe = messages::sanitize(text.as_bytes());
+    preview.set_text(&safe); // In case set_markup fails.
+    preview.set_markup(&markup::render(&safe, app.theme));
+}
 pub(crate) fn confirm<F>(window: &Window, message: &str, callback: F)
     where F: Fn() + 'static
 {
@@ -45,14 +937,14 @@ pub(crate) fn connect(app: &Rc<App>, addr: SocketAddr, hash: String, token: Opti
 {
     let result = app.connections.connect(addr, hash, token, || {
         let dialog = Dialog::new_with_buttons(
-            Some("Synac: Password dialog"),
+            Some(&*tr("Synac: Password dialog")),
             Some(&app.window),
             DialogFlags::MODAL,
-            &[("Ok", ResponseType::Ok.into())]
+            &[(&tr("_Ok"), ResponseType::Ok.into())]
         );
 
         let content = dialog.get_content_area();
-        content.add(&Label::new("Password:"));
+        content.add(&Label::new(&*tr("Password:")));
         let entry = Entry::new();
         entry.set_input_purpose(InputPurpose::Password);
         entry.set_visibility(false);
@@ -75,41 +967,330 @@ pub(crate) fn connect(app: &Rc<App>, addr: SocketAddr, hash: String, token: Opti
         },
         Err(err) => {
             deselect_server(app);
-            alert(&app.window, MessageType::Error, &format!("connection error: {}", err));
+            alert(&app.window, MessageType::Error, &fill(&tr("connection error: {}"), &[&err.to_string()]));
             Some(err)
         }
     }
 }
 pub(crate) fn deselect_server(app: &Rc<App>) {
     app.connections.set_current(None);
+    *app.direct.borrow_mut() = None;
+    app.channel_topic.set_tooltip_text(None::<&str>);
     app.message_edit.set_reveal_child(false);
     app.message_input.set_reveal_child(false);
+    switch_draft(app, None);
+    *app.history.borrow_mut() = History::default();
+    app.history_search.revealer.set_reveal_child(false);
+    reset_completion(app);
+    hide_command_output(app);
+    render_topic(app, None);
     app.server_name.set_text("");
     app.typing.set_text("");
     render_channels(app, None);
 }
+pub(crate) fn export_settings(app: &Rc<App>) {
+    let dialog = FileChooserDialog::new(Some(&*tr("Export settings")), Some(&app.window), FileChooserAction::Save);
+    dialog.add_button(&tr("_Cancel"), ResponseType::Cancel.into());
+    dialog.add_button(&tr("_Export"), ResponseType::Accept.into());
+    dialog.set_do_overwrite_confirmation(true);
+    dialog.set_current_name("synac-settings.sqlite");
+
+    let tokens = CheckButton::new_with_label(&tr("Include login tokens (anyone with the file can log in as you)"));
+    dialog.set_extra_widget(&tokens);
+    tokens.show();
+
+    let response = dialog.run();
+    let path = dialog.get_filename();
+    let tokens = tokens.get_active();
+    dialog.destroy();
+
+    if response != ResponseType::Accept.into() {
+        return;
+    }
+    let path = match path {
+        Some(path) => path,
+        None => return
+    };
+
+    let style = app.basedirs.find_config_file("style.css").and_then(|path| {
+        let mut style = String::new();
+        let result = File::open(path).and_then(|mut file| file.read_to_string(&mut style));
+        match result {
+            Ok(_) => Some(style),
+            Err(err) => {
+                eprintln!("error reading style.css: {}", err);
+                None
+            }
+        }
+    });
+
+    match config::export(&app.db, &path, tokens, style.as_ref().map(|style| &**style)) {
+        Ok(()) => alert(&app.window, MessageType::Info, &tr("Settings exported")),
+        Err(err) => alert(&app.window, MessageType::Error, &fill(&tr("failed to export settings: {}"), &[&err.to_string()]))
+    }
+}
+pub(crate) fn import_settings(app: &Rc<App>) {
+    let dialog = FileChooserDialog::new(Some(&*tr("Import settings")), Some(&app.window), FileChooserAction::Open);
+    dialog.add_button(&tr("_Cancel"), ResponseType::Cancel.into());
+    dialog.add_button(&tr("_Import"), ResponseType::Accept.into());
+
+    let replace = CheckButton::new_with_label(&tr("Replace servers, aliases and style.css that already exist"));
+    dialog.set_extra_widget(&replace);
+    replace.show();
+
+    let response = dialog.run();
+    let path = dialog.get_filename();
+    let replace = replace.get_active();
+    dialog.destroy();
+
+    if response != ResponseType::Accept.into() {
+        return;
+    }
+    let path = match path {
+        Some(path) => path,
+        None => return
+    };
+
+    let imported = match config::import(&app.db, &path, replace) {
+        Ok(imported) => imported,
+        Err(err) => {
+            alert(&app.window, MessageType::Error, &fill(&tr("failed to import settings: {}"), &[&err.to_string()]));
+            return;
+        }
+    };
+
+    let servers = imported.servers.to_string();
+    let mut string = fill(&trn("Imported {} server.", "Imported {} servers.", imported.servers as u64), &[&servers]);
+    if !imported.skipped.is_empty() {
+        string.push('\n');
+        string.push_str(&fill(&tr("Skipped servers that already exist: {}"), &[&imported.skipped.join(", ")]));
+    }
+
+    if let Some(style) = imported.style {
+        if replace || app.basedirs.find_config_file("style.css").is_none() {
+            let result = app.basedirs.place_config_file("style.css")
+                .and_then(File::create)
+                .and_then(|mut file| file.write_all(style.as_bytes()));
+            match result {
+                Ok(()) => {
+                    string.push('\n');
+                    string.push_str(&tr("The imported style.css is used after a restart."));
+                },
+                Err(err) => eprintln!("error writing style.css: {}", err)
+            }
+        }
+    }
+
+    if let Some(nick) = get_data(&app.db, "nick") {
+        app.user_name.set_text(&nick);
+        *app.connections.nick.write().unwrap() = nick;
+    }
+    *app.connections.logger.lock().unwrap() = load_logger(&app.db, &app.basedirs);
+    *app.time_format.borrow_mut() = load_time_format(&app.db);
+    *app.emoji_recent.borrow_mut() = load_emoji_recent(&app.db);
+    load_deleted_prefs(app);
+
+    rerender_messages(app);
+    render_servers(app);
+    alert(&app.window, MessageType::Info, &string);
+}
+pub(crate) fn load_aliases(db: &SqlConnection) -> Vec<(String, String)> {
+    let mut stmt = db.prepare_cached("SELECT name, expansion FROM aliases ORDER BY name").unwrap();
+    let mut rows = stmt.query(&[]).unwrap();
+
+    let mut aliases = Vec::new();
+    while let Some(row) = rows.next() {
+        let row = row.unwrap();
+        aliases.push((row.get(0), row.get(1)));
+    }
+    aliases
+}
+/// Replaces a `/name` or `!name` alias with what it expands to.
+/// Returns None if `text` doesn't start with one. Built-in commands always win.
+fn expand_alias(app: &Rc<App>, text: &str) -> Option<Result<String, String>> {
+    let call = alias::split(text)?;
+    if call.sigil == '/' && commands::find(&call.name).is_some() {
+        return None;
+    }
+    let template: String = {
+        let mut stmt = app.db.prepare_cached("SELECT expansion FROM aliases WHERE name = ?").unwrap();
+        let mut rows = stmt.query(&[&call.name]).unwrap();
+        let template = rows.next()?.unwrap().get(0);
+        template
+    };
+    Some(alias::expand(&template, &call).map_err(|err| alias_error(&err)))
+}
+fn alias_error(err: &AliasError) -> String {
+    match *err {
+        AliasError::InvalidName => tr("Alias names may only contain letters, digits, - and _."),
+        AliasError::Reserved(ref name) => fill(&tr("/{} is already a command."), &[name]),
+        AliasError::MissingArguments(needed) => fill(
+            &trn("The alias needs at least {} argument.", "The alias needs at least {} arguments.", needed as u64),
+            &[&needed.to_string()]
+        ),
+        AliasError::InvalidLine(line) => fill(
+            &tr("Line {}: expected an alias name followed by what it expands to."),
+            &[&line.to_string()]
+        ),
+        AliasError::Parse(ref err) => parse_error(err)
+    }
+}
+pub(crate) fn render_aliases(app: &Rc<App>) {
+    let list = &app.stack_aliases.list;
+    for child in list.get_children() {
+        list.remove(&child);
+    }
+    let aliases = load_aliases(&app.db);
+    if aliases.is_empty() {
+        list.add(&Label::new(&*tr("No aliases yet.")));
+    }
+    for (name, expansion) in aliases {
+        let row = GtkBox::new(Orientation::Horizontal, 10);
+
+        let label = Label::new(&*name);
+        add_class(&label, "bold");
+        row.add(&label);
+
+        let label = Label::new(&*expansion);
+        label.set_ellipsize(EllipsizeMode::End);
+        label.set_hexpand(true);
+        label.set_xalign(0.0);
+        row.add(&label);
+
+        let edit = Button::new_with_label(&tr("Edit"));
+        edit.set_relief(ReliefStyle::None);
+        let app_clone = Rc::clone(app);
+        let (name_clone, expansion_clone) = (name.clone(), expansion.clone());
+        edit.connect_clicked(move |_| {
+            app_clone.stack_aliases.name.set_text(&name_clone);
+            app_clone.stack_aliases.expansion.set_text(&expansion_clone);
+            app_clone.stack_aliases.expansion.grab_focus();
+        });
+        row.add(&edit);
+
+        let delete = Button::new_with_label(&tr("Delete"));
+        delete.set_relief(ReliefStyle::None);
+        let app_clone = Rc::clone(app);
+        delete.connect_clicked(move |_| {
+            app_clone.db.execute("DELETE FROM aliases WHERE name = ?", &[&name]).unwrap();
+            render_aliases(&app_clone);
+        });
+        row.add(&delete);
+
+        list.add(&row);
+    }
+    list.show_all();
+}
+/// Saves what's typed in the alias editor, replacing any alias with the same name.
+pub(crate) fn save_alias(app: &Rc<App>) {
+    let name = app.stack_aliases.name.get_text().unwrap_or_default();
+    let expansion = app.stack_aliases.expansion.get_text().unwrap_or_default();
+
+    let name = match alias::normalize(&name) {
+        Ok(name) => name,
+        Err(err) => {
+            app.stack_aliases.error.set_text(&alias_error(&err));
+            return;
+        }
+    };
+    let expansion = expansion.trim();
+    if expansion.is_empty() {
+        app.stack_aliases.error.set_text(&tr("Type what the alias expands to."));
+        return;
+    }
+    app.db.execute("REPLACE INTO aliases (name, expansion) VALUES (?, ?)", &[&name, &expansion]).unwrap();
+
+    app.stack_aliases.error.set_text("");
+    app.stack_aliases.name.set_text("");
+    app.stack_aliases.expansion.set_text("");
+    app.stack_aliases.name.grab_focus();
+    render_aliases(app);
+}
+pub(crate) fn export_aliases(app: &Rc<App>) {
+    let dialog = FileChooserDialog::new(Some(&*tr("Export aliases")), Some(&app.window), FileChooserAction::Save);
+    dialog.add_button(&tr("_Cancel"), ResponseType::Cancel.into());
+    dialog.add_button(&tr("_Export"), ResponseType::Accept.into());
+    dialog.set_do_overwrite_confirmation(true);
+    dialog.set_current_name("synac-aliases.txt");
+
+    let response = dialog.run();
+    let path = dialog.get_filename();
+    dialog.destroy();
+
+    if response != ResponseType::Accept.into() {
+        return;
+    }
+    let path = match path {
+        Some(path) => path,
+        None => return
+    };
+
+    let text = alias::export(&load_aliases(&app.db));
+    match File::create(path).and_then(|mut file| file.write_all(text.as_bytes())) {
+        Ok(()) => alert(&app.window, MessageType::Info, &tr("Aliases exported")),
+        Err(err) => alert(&app.window, MessageType::Error, &fill(&tr("failed to export aliases: {}"), &[&err.to_string()]))
+    }
+}
+/// Reads aliases from a file written by `export_aliases`, replacing any with the same name.
+pub(crate) fn import_aliases(app: &Rc<App>) {
+    let dialog = FileChooserDialog::new(Some(&*tr("Import aliases")), Some(&app.window), FileChooserAction::Open);
+    dialog.add_button(&tr("_Cancel"), ResponseType::Cancel.into());
+    dialog.add_button(&tr("_Import"), ResponseType::Accept.into());
+
+    let response = dialog.run();
+    let path = dialog.get_filename();
+    dialog.destroy();
+
+    if response != ResponseType::Accept.into() {
+        return;
+    }
+    let path = match path {
+        Some(path) => path,
+        None => return
+    };
+
+    let mut text = String::new();
+    if let Err(err) = File::open(path).and_then(|mut file| file.read_to_string(&mut text)) {
+        alert(&app.window, MessageType::Error, &fill(&tr("failed to import aliases: {}"), &[&err.to_string()]));
+        return;
+    }
+    let aliases = match alias::import(&text) {
+        Ok(aliases) => aliases,
+        Err(err) => {
+            alert(&app.window, MessageType::Error, &fill(&tr("failed to import aliases: {}"), &[&alias_error(&err)]));
+            return;
+        }
+    };
+    for &(ref name, ref expansion) in &aliases {
+        app.db.execute("REPLACE INTO aliases (name, expansion) VALUES (?, ?)", &[name, expansion]).unwrap();
+    }
+
+    render_aliases(app);
+    let count = aliases.len();
+    alert(&app.window, MessageType::Info, &fill(&trn("Imported {} alias.", "Imported {} aliases.", count as u64), &[&count.to_string()]));
+}
 pub(crate) fn render_mode(container: &GtkBox, bitmask: u8) {
     for child in container.get_children() {
         container.remove(&child);
     }
 
-    let mut check = CheckButton::new_with_label("Read messages");
+    let mut check = CheckButton::new_with_label(&tr("Read messages"));
     check.set_active(bitmask & common::PERM_READ == common::PERM_READ);
     container.add(&check);
 
-    check = CheckButton::new_with_label("Write messages");
+    check = CheckButton::new_with_label(&tr("Write messages"));
     check.set_active(bitmask & common::PERM_WRITE == common::PERM_WRITE);
     container.add(&check);
 
-    check = CheckButton::new_with_label("Manage channel");
+    check = CheckButton::new_with_label(&tr("Manage channel"));
     check.set_active(bitmask & common::PERM_MANAGE_CHANNELS == common::PERM_MANAGE_CHANNELS);
     container.add(&check);
 
-    check = CheckButton::new_with_label("Manage messages");
+    check = CheckButton::new_with_label(&tr("Manage messages"));
     check.set_active(bitmask & common::PERM_MANAGE_MESSAGES == common::PERM_MANAGE_MESSAGES);
     container.add(&check);
 
-    check = CheckButton::new_with_label("Manage user modes");
+    check = CheckButton::new_with_label(&tr("Manage user modes"));
     check.set_active(bitmask & common::PERM_MANAGE_MODES == common::PERM_MANAGE_MODES);
     container.add(&check);
 
@@ -138,6 +1319,17 @@ pub(crate) fn get_mode(container: &GtkBox) -> Option<u8> {
 
     Some(bitmask)
 }
+/// Returns true if we may manage other people's messages in the current channel.
+pub(crate) fn can_manage_messages(synac: &Synac) -> bool {
+    let channel = match synac.current_channel.and_then(|id| synac.state.channels.get(&id)) {
+        Some(channel) => channel,
+        None => return false
+    };
+    match synac.state.users.get(&synac.user) {
+        Some(user) => synac::get_mode(channel, user) & common::PERM_MANAGE_MESSAGES == common::PERM_MANAGE_MESSAGES,
+        None => false
+    }
+}
 pub(crate) fn select_channel(app: &Rc<App>, synac: &mut Synac, channel_id: usize) {
     // Scope here so channel_name is dropped.
     // Can't wait for non-lexical lifetimes!
@@ -158,7 +1350,21 @@ pub(crate) fn select_channel(app: &Rc<App>, synac: &mut Synac, channel_id: usize
 
     app.message_input.set_reveal_child(mode & common::PERM_WRITE == common::PERM_WRITE);
 
+    // Private conversation rows have their own ids, so don't let them be mistaken for messages
+    if app.direct.borrow_mut().take().is_some() {
+        clear_messages(app);
+        app.channel_topic.set_tooltip_text(None::<&str>);
+    }
     synac.current_channel = Some(channel_id);
+    switch_draft(app, Some((synac.addr, channel_id)));
+    *app.history.borrow_mut() = load_history(&app.db, synac.addr, channel_id);
+    reset_completion(app);
+    app.history_search.revealer.set_reveal_child(false);
+    hide_command_output(app);
+    render_topic(app, Some((synac.addr, channel_id)));
+    *app.scroll_target.borrow_mut() = None;
+    *app.gap_fill.borrow_mut() = None;
+    app.loading_older.set(false);
     app.channel_name.set_text(&channel_name);
     app.typing.set_text("");
 
@@ -178,10 +1384,122 @@ pub(crate) fn select_channel(app: &Rc<App>, synac: &mut Synac, channel_id: usize
 
     render_messages(app, Some(synac));
     render_users(app,    Some(synac));
+    render_bots(app,     Some(synac));
 
     scroll_to_bottom(app);
 }
+/// Counts a message of the list `fill_gap` is waiting for.
+pub(crate) fn count_gap(app: &Rc<App>, addr: SocketAddr, msg: &common::Message) {
+    if let Some(ref mut gap) = *app.gap_fill.borrow_mut() {
+        if gap.addr == addr && gap.channel == msg.channel && gap.skip == 0 {
+            gap.count += 1;
+            gap.newest = cmp::max(gap.newest, Some(msg.id));
+        }
+    }
+}
+/// Runs at the end of every message list. After the page following a linked message,
+/// asks for the next one, until a page comes back short because it reached the latest message.
+/// Returns true if the list was one of those pages.
+pub(crate) fn fill_gap(app: &Rc<App>, synac: &mut Synac) -> bool {
+    let mut gap_fill = app.gap_fill.borrow_mut();
+    let done = match *gap_fill {
+        Some(ref mut gap) => if gap.addr != synac.addr {
+            return false;
+        } else if gap.skip > 0 {
+            gap.skip -= 1;
+            return false;
+        } else if gap.count < common::LIMIT_BULK || gap.newest.is_none() {
+            true
+        } else {
+            let result = synac.session.send(&Packet::MessageList(common::MessageList {
+                after: gap.newest,
+                before: None,
+                channel: gap.channel,
+                limit: common::LIMIT_BULK
+            }));
+            gap.count = 0;
+            if let Err(err) = result {
+                eprintln!("error sending packet: {}", err);
+                true
+            } else {
+                false
+            }
+        },
+        None => return false
+    };
+    if done {
+        *gap_fill = None;
+    }
+    true
+}
+/// Scrolls to and highlights the message a permalink pointed to, once it's loaded.
+pub(crate) fn scroll_to_target(app: &Rc<App>) {
+    // Wait until messages are properly rendered
+
+    let app = Rc::clone(app);
+    gtk::idle_add(move || {
+        let target = match *app.scroll_target.borrow() {
+            Some(target) => target,
+            None => return Continue(false)
+        };
+        let rows = app.message_rows.borrow();
+        if let Some(row) = rows.iter().find(|row| row.id == target) {
+            if let Some(vadjustment) = app.messages_scroll.get_vadjustment() {
+                let y = row.row.get_allocation().y as f64;
+                vadjustment.set_value(y - vadjustment.get_page_size() / 3.0);
+            }
+
+            add_class(&row.row, "target");
+            let row = row.row.clone();
+            gtk::timeout_add_seconds(3, move || {
+                if let Some(context) = row.get_style_context() {
+                    context.remove_class("target");
+                }
+                Continue(false)
+            });
+
+            *app.scroll_target.borrow_mut() = None;
+        }
+        Continue(false)
+    });
+}
+/// Remembers the first visible message, before older ones are prepended above it.
+pub(crate) fn set_scroll_anchor(app: &Rc<App>) {
+    let vadjustment = match app.messages_scroll.get_vadjustment() {
+        Some(vadjustment) => vadjustment,
+        None => return
+    };
+    let value = vadjustment.get_value();
+    let anchor = app.message_rows.borrow().iter()
+        .map(|row| (row.id, row.row.get_allocation()))
+        .find(|&(_, ref allocation)| (allocation.y + allocation.height) as f64 > value)
+        .map(|(id, allocation)| (id, allocation.y as f64 - value));
+    if anchor.is_none() {
+        return;
+    }
+    app.scroll_anchor.set(anchor);
+
+    // Wait until messages are properly rendered
+
+    let app = Rc::clone(app);
+    gtk::idle_add(move || {
+        if let Some((id, offset)) = app.scroll_anchor.get() {
+            app.scroll_anchor.set(None);
+
+            let rows = app.message_rows.borrow();
+            if let Some(row) = rows.iter().find(|row| row.id == id) {
+                if let Some(vadjustment) = app.messages_scroll.get_vadjustment() {
+                    vadjustment.set_value(row.row.get_allocation().y as f64 - offset);
+                }
+            }
+        }
+        Continue(false)
+    });
+}
 pub(crate) fn scroll_to_bottom(app: &Rc<App>) {
+    app.scroll_anchor.set(None);
+    app.stick_to_bottom.set(true);
+
     // Wait until messages are properly rendered
 
     let app = Rc::clone(app);
@@ -189,9 +1507,183 @@ pub(crate) fn scroll_to_bottom(app: &Rc<App>) {
         if let Some(vadjustment) = app.messages_scroll.get_vadjustment() {
             vadjustment.set_value(vadjustment.get_upper());
         }
-        // Only do this once.
-        Continue(false)
+        // Only do this once.
+        Continue(false)
+    });
+}
+/// Switches to a server, connecting to it first if needed. Returns false on failure.
+pub(crate) fn select_server(app: &Rc<App>, addr: SocketAddr, name: &str, hash: &str, token: &Option<String>)
+    -> bool
+{
+    deselect_server(app);
+    let mut err = true;
+    app.connections.execute(addr, |result| {
+        err = result.is_err();
+
+        if let Ok(synac) = result {
+            render_channels(app, Some(synac));
+            app.connections.set_current(Some(addr));
+            app.message_edit.set_reveal_child(false);
+
+            let channel_id = {
+                let mut channels: Vec<_> = synac.state.channels.values().collect();
+                channels.sort_by_key(|channel| &channel.name);
+
+                channels.first().map(|channel| channel.id)
+            };
+
+            if let Some(channel_id) = channel_id {
+                select_channel(app, synac, channel_id);
+            }
+        }
+    });
+    if err {
+        err = connect(app, addr, hash.to_string(), token.clone()).is_some();
+        render_channels(app, None);
+    }
+    if !err {
+        app.server_name.set_text(name);
+    }
+    !err
+}
+/// Opens a link clicked in a message. Only web links are passed on to
+/// the desktop, since anything else could run programs or read files.
+pub(crate) fn open_link(app: &Rc<App>, uri: &str) {
+    let scheme = uri.find("://").map(|i| uri[..i].to_lowercase());
+    match scheme.as_ref().map(|scheme| &**scheme) {
+        Some("http") | Some("https") => {
+            if let Err(err) = gtk::show_uri(None, uri, gtk::get_current_event_time()) {
+                alert(&app.window, MessageType::Error, &fill(&tr("failed to open link: {}"), &[&err.to_string()]));
+            }
+        },
+        Some("synac") => match Permalink::parse(uri) {
+            Ok(link) => open_permalink(app, &link),
+            Err(err) => alert(&app.window, MessageType::Error, &fill(&tr("Invalid link: {}"), &[&err.to_string()]))
+        },
+        _ => alert(&app.window, MessageType::Warning, &fill(&tr("Refusing to open unsafe link: {}"), &[uri]))
+    }
+}
+/// Looks up a saved server, returning its name, hash and token.
+fn find_server(db: &SqlConnection, addr: SocketAddr) -> Option<(String, String, Option<String>)> {
+    let mut stmt = db.prepare_cached("SELECT name, hash, token FROM servers WHERE ip = ?").unwrap();
+    let mut rows = stmt.query(&[&addr.to_string()]).unwrap();
+
+    rows.next().map(|row| {
+        let row = row.unwrap();
+        (row.get(0), row.get(1), row.get(2))
+    })
+}
+/// Builds a link to a server, channel or message that others can open.
+pub(crate) fn permalink(app: &Rc<App>, addr: SocketAddr, channel: Option<usize>, message: Option<usize>) -> String {
+    Permalink {
+        host: addr.to_string(),
+        channel: channel,
+        message: message,
+        hash: find_server(&app.db, addr).map(|(_, hash, _)| hash)
+    }.to_string()
+}
+/// Opens a synac:// link, adding the server first if the link says how to trust it.
+pub(crate) fn open_permalink(app: &Rc<App>, link: &Permalink) {
+    let addr = match connections::parse_addr(&link.host) {
+        Some(addr) => addr,
+        None => {
+            alert(&app.window, MessageType::Error, &fill(&tr("Failed to resolve server {}"), &[&link.host]));
+            return;
+        }
+    };
+
+    match find_server(&app.db, addr) {
+        Some((_, hash, _)) => {
+            if link.hash.as_ref().map(|expected| *expected != hash).unwrap_or(false) {
+                alert(&app.window, MessageType::Error, &fill(
+                    &tr("The link's hash doesn't match the one saved for {}, so it wasn't opened."),
+                    &[&link.host]
+                ));
+                return;
+            }
+            follow_permalink(app, addr, link);
+        },
+        None => {
+            let hash = match link.hash {
+                Some(ref hash) => hash.clone(),
+                None => {
+                    alert(&app.window, MessageType::Info, &fill(
+                        &tr("You haven't added the server {} yet, and the link doesn't say how to trust it."),
+                        &[&link.host]
+                    ));
+                    return;
+                }
+            };
+            let app_clone = Rc::clone(app);
+            let link = link.clone();
+            confirm(&app.window, &fill(&tr("Add the server {} and connect to it?"), &[&link.host]), move || {
+                app_clone.db.execute(
+                    "INSERT INTO servers (name, ip, hash) VALUES (?, ?, ?)",
+                    &[&link.host, &addr.to_string(), &hash]
+                ).unwrap();
+                render_servers(&app_clone);
+                follow_permalink(&app_clone, addr, &link);
+            });
+        }
+    }
+}
+fn follow_permalink(app: &Rc<App>, addr: SocketAddr, link: &Permalink) {
+    let (name, hash, token) = match find_server(&app.db, addr) {
+        Some(server) => server,
+        None => return
+    };
+    if !select_server(app, addr, &name, &hash, &token) {
+        return;
+    }
+    let channel = match link.channel {
+        Some(channel) => channel,
+        None => return
+    };
+
+    app.connections.execute(addr, |result| {
+        if result.is_err() { return; }
+        let synac = result.unwrap();
+
+        if !synac.state.channels.contains_key(&channel) {
+            alert(&app.window, MessageType::Error, &tr("That channel doesn't exist, or you can't see it."));
+            return;
+        }
+        // select_channel asks for the latest messages if it has none yet
+        let latest_requested = !synac.messages.has(channel);
+        select_channel(app, synac, channel);
+
+        if let Some(message) = link.message {
+            *app.scroll_target.borrow_mut() = Some(message);
+
+            if synac.messages.find(message).is_none() {
+                // Load the message with what came before and after it
+                let before = synac.session.send(&Packet::MessageList(common::MessageList {
+                    after: None,
+                    before: Some(message + 1),
+                    channel: channel,
+                    limit: common::LIMIT_BULK
+                }));
+                let after = before.and_then(|_| synac.session.send(&Packet::MessageList(common::MessageList {
+                    after: Some(message),
+                    before: None,
+                    channel: channel,
+                    limit: common::LIMIT_BULK
+                })));
+                match after {
+                    // Between that and the latest messages there might be more, which fill_gap loads
+                    Ok(()) => *app.gap_fill.borrow_mut() = Some(GapFill {
+                        addr: addr,
+                        channel: channel,
+                        skip: if latest_requested { 2 } else { 1 },
+                        count: 0,
+                        newest: None
+                    }),
+                    Err(err) => eprintln!("error sending packet: {}", err)
+                }
+            }
+        }
     });
+    scroll_to_target(app);
 }
 pub(crate) fn render_servers(app: &Rc<App>) {
     for child in app.servers.get_children() {
@@ -219,40 +1711,12 @@ pub(crate) fn render_servers(app: &Rc<App>) {
             let addr = match ip_parsed {
                 Some(addr) => addr,
                 None => {
-                    alert(&app_clone.window, MessageType::Error, "Failed to parse IP address. Format: <ip[:port]>");
+                    alert(&app_clone.window, MessageType::Error, &tr("Failed to parse IP address. Format: <ip[:port]>"));
                     return;
                 }
             };
             println!("server with ip {} was clicked", addr);
-            deselect_server(&app_clone);
-            let mut err = true;
-            app_clone.connections.execute(addr, |result| {
-                err = result.is_err();
-
-                if let Ok(synac) = result {
-                    render_channels(&app_clone, Some(synac));
-                    app_clone.connections.set_current(Some(addr));
-                    app_clone.message_edit.set_reveal_child(false);
-
-                    let channel_id = {
-                        let mut channels: Vec<_> = synac.state.channels.values().collect();
-                        channels.sort_by_key(|channel| &channel.name);
-
-                        channels.first().map(|channel| channel.id)
-                    };
-
-                    if let Some(channel_id) = channel_id {
-                        select_channel(&app_clone, synac, channel_id);
-                    }
-                }
-            });
-            if err {
-                err = connect(&app_clone, addr, (*hash_clone).clone(), (*token_clone).clone()).is_some();
-                render_channels(&app_clone, None);
-            }
-            if !err {
-                app_clone.server_name.set_text(&name_clone);
-            }
+            select_server(&app_clone, addr, &name_clone, &hash_clone, &token_clone);
         });
 
         let app_clone = Rc::clone(app);
@@ -264,7 +1728,7 @@ pub(crate) fn render_servers(app: &Rc<App>) {
                 let name: Rc<String> = Rc::clone(&name);
                 let hash: Rc<String> = Rc::clone(&hash);
 
-                let edit = MenuItem::new_with_label("Edit server");
+                let edit = MenuItem::new_with_label(&tr("Edit server"));
                 let app_clone2 = Rc::clone(&app_clone);
                 edit.connect_activate(move |_| {
                     app_clone2.stack_edit_server.name.set_text(&name);
@@ -276,7 +1740,7 @@ pub(crate) fn render_servers(app: &Rc<App>) {
                 });
                 menu.add(&edit);
 
-                let disconnect = MenuItem::new_with_label("Disconnect server");
+                let disconnect = MenuItem::new_with_label(&tr("Disconnect server"));
 
                 let app_clone2 = Rc::clone(&app_clone);
                 disconnect.connect_activate(move |_| {
@@ -290,7 +1754,7 @@ pub(crate) fn render_servers(app: &Rc<App>) {
                 });
                 menu.add(&disconnect);
 
-                let forget = MenuItem::new_with_label("Forget server");
+                let forget = MenuItem::new_with_label(&tr("Forget server"));
 
                 let app_clone2 = Rc::clone(&app_clone);
                 let addr = Rc::clone(&addr);
@@ -303,6 +1767,10 @@ pub(crate) fn render_servers(app: &Rc<App>) {
                             deselect_server(&app_clone2);
                         }
                     }
+                    app_clone2.db.execute("DELETE FROM drafts WHERE server = ?", &[&*addr]).unwrap();
+                    app_clone2.db.execute("DELETE FROM history WHERE server = ?", &[&*addr]).unwrap();
+                    app_clone2.db.execute("DELETE FROM public_keys WHERE server = ?", &[&*addr]).unwrap();
+                    app_clone2.db.execute("DELETE FROM private_messages WHERE server = ?", &[&*addr]).unwrap();
                     render_servers(&app_clone2);
                 });
                 menu.add(&forget);
@@ -321,6 +1789,7 @@ pub(crate) fn render_channels(app: &Rc<App>, synac: Option<&mut Synac>) {
     for child in app.channels.get_children() {
         app.channels.remove(&child);
     }
+    app.typing_channels.borrow_mut().clear();
     if let Some(synac) = synac {
         let addr = synac.addr;
 
@@ -332,9 +1801,16 @@ pub(crate) fn render_channels(app: &Rc<App>, synac: Option<&mut Synac>) {
             name.push('#');
             name.push_str(&channel.name);
 
-            let button = Button::new_with_label(&name);
+            let button = Button::new();
+            let content = GtkBox::new(Orientation::Horizontal, 4);
+            content.set_halign(Align::Center);
+            content.add(&Label::new(&*name));
+            let indicator = typing_indicator();
+            content.add(&indicator);
+            button.add(&content);
 
             let channel_id = channel.id;
+            app.typing_channels.borrow_mut().insert(channel_id, indicator);
 
             let app_clone = Rc::clone(app);
             button.connect_clicked(move |_| {
@@ -365,7 +1841,7 @@ pub(crate) fn render_channels(app: &Rc<App>, synac: Option<&mut Synac>) {
                     });
 
                     if mode & common::PERM_MANAGE_CHANNELS == common::PERM_MANAGE_CHANNELS {
-                        let edit = MenuItem::new_with_label("Edit channel");
+                        let edit = MenuItem::new_with_label(&tr("Edit channel"));
 
                         let app_clone1 = Rc::clone(&app_clone);
                         edit.connect_activate(move |_| {
@@ -386,7 +1862,7 @@ pub(crate) fn render_channels(app: &Rc<App>, synac: Option<&mut Synac>) {
 
                         menu.add(&edit);
 
-                        let delete = MenuItem::new_with_label("Delete channel");
+                        let delete = MenuItem::new_with_label(&tr("Delete channel"));
 
                         let app_clone2 = Rc::clone(&app_clone);
                         delete.connect_activate(move |_| {
@@ -414,11 +1890,20 @@ pub(crate) fn render_channels(app: &Rc<App>, synac: Option<&mut Synac>) {
                         |row| row.get(0)
                     ).unwrap();
 
-                    let mute = MenuItem::new_with_label(if count == 0 {
+                    let link = MenuItem::new_with_label(&tr("Copy link to channel"));
+
+                    let app_clone4 = Rc::clone(&app_clone);
+                    link.connect_activate(move |_| {
+                        let link = permalink(&app_clone4, addr, Some(channel_id), None);
+                        Clipboard::get(&gdk::SELECTION_CLIPBOARD).set_text(&link);
+                    });
+                    menu.add(&link);
+
+                    let mute = MenuItem::new_with_label(&tr(if count == 0 {
                         "Mute channel"
                     } else {
                         "Unmute channel"
-                    });
+                    }));
 
                     let app_clone3 = Rc::clone(&app_clone);
                     mute.connect_activate(move |_| {
@@ -445,148 +1930,1004 @@ pub(crate) fn render_channels(app: &Rc<App>, synac: Option<&mut Synac>) {
         if let Some(user) = synac.state.users.get(&synac.user) {
             app.channel_add.set_reveal_child(user.admin);
         }
+        render_direct(app, Some(synac));
+        render_typing(app, synac);
     } else {
         app.channel_add.set_reveal_child(false);
+        render_direct(app, None);
         app.channel_name.set_text("");
         render_messages(app, None);
         render_users(app,    None);
+        render_bots(app,     None);
     }
 
     app.channels.show_all();
     app.channels.queue_draw();
 }
-pub(crate) fn render_messages(app: &Rc<App>, synac: Option<&mut Synac>) {
-    for child in app.messages.get_children() {
-        app.messages.remove(&child);
+/// Loads our private key, making one the first time.
+pub(crate) fn load_private_key(db: &SqlConnection) -> PKey {
+    if let Some(pem) = get_data(db, "private_key") {
+        match Rsa::private_key_from_pem(pem.as_bytes()).and_then(PKey::from_rsa) {
+            Ok(key) => return key,
+            Err(err) => eprintln!("error reading private key, making a new one: {}", err)
+        }
     }
-    if let Some(synac) = synac {
-        let addr = synac.addr;
-        if let Some(channel) = synac.current_channel {
-            let mut last: Option<&common::Message> = None;
+    let key = e2e::generate().expect("Couldn't generate a private key");
+    let pem = key.rsa()
+        .and_then(|rsa| rsa.private_key_to_pem())
+        .expect("Couldn't encode the private key");
+    set_data(db, "private_key", &String::from_utf8_lossy(&pem));
+    key
+}
+fn public_key(db: &SqlConnection, addr: SocketAddr, user: usize) -> Option<PublicKey> {
+    let mut stmt = db.prepare_cached(
+        "SELECT key, verified, pending FROM public_keys WHERE server = ? AND user = ?"
+    ).unwrap();
+    let mut rows = stmt.query(&[&addr.to_string(), &(user as i64)]).unwrap();
 
-            for msg in synac.messages.get(channel) {
-                let msgbox = GtkBox::new(Orientation::Vertical, 2);
-                let authorbox = GtkBox::new(Orientation::Horizontal, 4);
+    rows.next().map(|row| {
+        let row = row.unwrap();
+        PublicKey {
+            der: row.get(0),
+            verified: row.get::<_, i64>(1) != 0,
+            pending: row.get(2)
+        }
+    })
+}
+/// Sends our public key, so `user` can write to us.
+fn send_public_key(app: &Rc<App>, synac: &mut Synac, user: usize) {
+    let der = match e2e::public_der(&app.private_key) {
+        Ok(der) => der,
+        Err(err) => {
+            eprintln!("error encoding public key: {}", err);
+            return;
+        }
+    };
+    if let Err(err) = synac.session.send(&Packet::PrivateMessage(common::PrivateMessage {
+        text: e2e::encode_key(&der),
+        recipient: user
+    })) {
+        eprintln!("failed to send packet: {}", err);
+    }
+}
+fn store_private(db: &SqlConnection, addr: SocketAddr, user: usize, author: usize, text: &str, timestamp: i64) {
+    db.execute(
+        "INSERT INTO private_messages (server, user, author, text, timestamp) VALUES (?, ?, ?, ?, ?)",
+        &[&addr.to_string(), &(user as i64), &(author as i64), &text, &timestamp]
+    ).unwrap();
+}
+/// Stores a message `user` wrote, unless its nonce shows it's a copy of one we already have.
+/// Returns whether it was new.
+fn store_opened(db: &SqlConnection, addr: SocketAddr, user: usize, opened: &e2e::Opened) -> bool {
+    let new = db.execute(
+        "INSERT OR IGNORE INTO seen_nonces (server, user, nonce) VALUES (?, ?, ?)",
+        &[&addr.to_string(), &(user as i64), &opened.nonce.to_vec()]
+    ).unwrap() == 1;
+    if new {
+        store_private(db, addr, user, user, &opened.text, opened.timestamp);
+    }
+    new
+}
+/// Keeps a private message that can't be checked yet, until their key arrives or is verified.
+fn queue_private(db: &SqlConnection, addr: SocketAddr, user: usize, sealed: &[u8]) {
+    db.execute(
+        "INSERT INTO queued_private (server, user, data) VALUES (?, ?, ?)",
+        &[&addr.to_string(), &(user as i64), &sealed.to_vec()]
+    ).unwrap();
+}
+/// Opens what `queue_private` kept with the key now in use for `user`, and stores it.
+/// Whatever that key didn't sign is dropped. Returns the texts that were opened.
+fn open_queued(app: &Rc<App>, addr: SocketAddr, user: usize, name: &str) -> Vec<String> {
+    let key = match public_key(&app.db, addr, user) {
+        Some(key) => key.der,
+        None => return Vec::new()
+    };
+    let mut queued = Vec::new();
+    {
+        let mut stmt = app.db.prepare_cached(
+            "SELECT rowid, data FROM queued_private WHERE server = ? AND user = ? ORDER BY rowid"
+        ).unwrap();
+        let mut rows = stmt.query(&[&addr.to_string(), &(user as i64)]).unwrap();
+        while let Some(row) = rows.next() {
+            let row = row.unwrap();
+            queued.push((row.get::<_, i64>(0), row.get::<_, Vec<u8>>(1)));
+        }
+    }
 
-                if last.map(|msg| msg.author) != Some(msg.author)
-                    || last.map(|msg| msg.timestamp + 60*5) < Some(msg.timestamp) {
-                    if last.is_some() {
-                        app.messages.add(&Separator::new(Orientation::Vertical));
-                    }
+    let mut opened = Vec::new();
+    for (rowid, sealed) in queued {
+        match e2e::open(&sealed, &app.private_key, &key) {
+            Ok(message) => {
+                if store_opened(&app.db, addr, user, &message) {
+                    opened.push(message.text);
+                } else {
+                    eprintln!("dropping replayed private message from {}", name);
+                }
+            },
+            Err(err) => eprintln!("dropping queued private message from {}: {}", name, err)
+        }
+        app.db.execute("DELETE FROM queued_private WHERE rowid = ?", &[&rowid]).unwrap();
+    }
+    opened
+}
+/// Lets the user know about a private message if they're looking at something else.
+fn notify_private(app: &Rc<App>, name: &str, text: &str) {
+    if app.window.is_active() {
+        return;
+    }
+    // Notification servers may interpret the body as markup
+    let mut body = String::with_capacity(text.len());
+    markup::escape(&mut body, &messages::sanitize(text.as_bytes()));
+
+    if let Err(err) = Notification::new().summary(&format!("@{}", name)).body(&body).show() {
+        eprintln!("error showing notification: {}", err);
+    }
+}
+/// Shows the private conversation with `user` where channels are normally shown.
+pub(crate) fn open_direct(app: &Rc<App>, synac: &mut Synac, user: usize) {
+    synac.current_channel = None;
+    *app.direct.borrow_mut() = Some((synac.addr, user));
+
+    switch_draft(app, None);
+    *app.history.borrow_mut() = History::default();
+    reset_completion(app);
+    app.history_search.revealer.set_reveal_child(false);
+    hide_command_output(app);
+    *app.scroll_target.borrow_mut() = None;
+    app.loading_older.set(false);
+    app.typing.set_text("");
+    app.messages_noread.set_reveal_child(false);
+    app.message_input.set_reveal_child(true);
+
+    let name = synac.state.users.get(&user).map(|user| user.name.clone()).unwrap_or_default();
+    app.channel_name.set_text(&format!("@{}", name));
+
+    if public_key(&app.db, synac.addr, user).is_none() {
+        send_public_key(app, synac, user);
+        show_command_output(
+            app,
+            &fill(&tr("Waiting for {}'s key. Their client sends it back as soon as they're online."), &[&name]),
+            false
+        );
+    }
+
+    render_private(app, synac);
+    render_direct(app, Some(synac));
+    render_users(app, Some(synac));
+    render_bots(app,  Some(synac));
+    scroll_to_bottom(app);
+}
+/// Shows whose key the open conversation is encrypted with, in place of the topic.
+fn render_key_status(app: &Rc<App>, addr: SocketAddr, user: usize) {
+    let status = match public_key(&app.db, addr, user) {
+        Some(PublicKey { pending: Some(ref pending), .. }) =>
+            fill(&tr("Their key changed to {} - right-click the conversation to verify it before writing"), &[&e2e::fingerprint(pending)]),
+        Some(PublicKey { ref der, verified: true, .. }) => fill(&tr("Verified key {}"), &[&e2e::fingerprint(der)]),
+        Some(PublicKey { ref der, .. }) =>
+            fill(&tr("Unverified key {} - right-click the conversation to verify it"), &[&e2e::fingerprint(der)]),
+        None => tr("No key yet")
+    };
+    app.channel_topic.set_text(&status);
+    app.channel_topic.set_tooltip_text(&*status);
+}
+/// Rebuilds the open private conversation from what's stored locally,
+/// since the server can't read it and doesn't keep it.
+pub(crate) fn render_private(app: &Rc<App>, synac: &Synac) {
+    let (addr, user) = match *app.direct.borrow() {
+        Some(direct) if direct.0 == synac.addr => direct,
+        _ => return
+    };
+    clear_messages(app);
+    render_key_status(app, addr, user);
+
+    let mut stmt = app.db.prepare_cached(
+        "SELECT rowid, author, text, timestamp FROM private_messages WHERE server = ? AND user = ? ORDER BY rowid"
+    ).unwrap();
+    let mut rows = stmt.query(&[&addr.to_string(), &(user as i64)]).unwrap();
+
+    let mut message_rows = app.message_rows.borrow_mut();
+    let mut last: Option<(usize, i64)> = None;
+    while let Some(row) = rows.next() {
+        let row = row.unwrap();
+        let id: i64 = row.get(0);
+        let author: i64 = row.get(1);
+        let text: String = row.get(2);
+        let timestamp: i64 = row.get(3);
+        let author = author as usize;
+
+        let name = author_name(synac, author);
+
+        let day = if starts_day(timestamp, last.map(|(_, last)| last)) {
+            let day = render_day(app, timestamp);
+            app.messages.add(&day.row);
+            Some(day)
+        } else {
+            None
+        };
+
+        let msgbox = GtkBox::new(Orientation::Vertical, 2);
+        render_header(app, &msgbox, &name, author, timestamp, None, last);
+        msgbox.add(&render_text(app, text.as_bytes()));
+
+        let list_row = ListBoxRow::new();
+        list_row.set_activatable(false);
+        list_row.set_selectable(false);
+        list_row.add(&msgbox);
+        list_row.show_all();
+        app.messages.add(&list_row);
+
+        let prev = message_rows.last().map(|row| row.id);
+        message_rows.push(MessageRow {
+            id: id as usize,
+            prev: prev,
+            edited: None,
+            deleted: false,
+            author: name,
+            text: text_hash(text.as_bytes()),
+            day: day,
+            row: list_row
+        });
+        last = Some((author, timestamp));
+    }
+}
+/// Lists the private conversations on a server, below its channels.
+pub(crate) fn render_direct(app: &Rc<App>, synac: Option<&Synac>) {
+    for child in app.direct_messages.get_children() {
+        app.direct_messages.remove(&child);
+    }
+    let synac = match synac {
+        Some(synac) => synac,
+        None => return
+    };
+    let addr = synac.addr;
+
+    let mut stmt = app.db.prepare_cached(
+        "SELECT user FROM public_keys WHERE server = ? UNION SELECT user FROM private_messages WHERE server = ?"
+    ).unwrap();
+    let mut rows = stmt.query(&[&addr.to_string(), &addr.to_string()]).unwrap();
+
+    let mut users = Vec::new();
+    while let Some(row) = rows.next() {
+        let id: i64 = row.unwrap().get(0);
+        let id = id as usize;
+        let name = synac.state.users.get(&id).map(|user| user.name.clone()).unwrap_or_else(|| String::from("unknown"));
+        users.push((id, name));
+    }
+    users.sort_by_key(|&(_, ref name)| name.to_lowercase());
+
+    let label = Label::new(&*tr("Direct messages"));
+    add_class(&label, "bold");
+    label.set_margin_top(10);
+    app.direct_messages.add(&label);
+
+    for (id, name) in users {
+        // Shown even when the conversation isn't open, since nothing can be sent until it's verified
+        let changed = public_key(&app.db, addr, id).map(|key| key.pending.is_some()).unwrap_or(false);
+        let button = if changed {
+            let button = Button::new_with_label(&fill(&tr("@{} (key changed)"), &[&name]));
+            button.set_tooltip_text(&*tr("Right-click to verify their new key"));
+            button
+        } else {
+            Button::new_with_label(&format!("@{}", name))
+        };
+
+        let app_clone = Rc::clone(app);
+        button.connect_clicked(move |_| {
+            app_clone.connections.execute(addr, |result| {
+                if let Ok(synac) = result {
+                    open_direct(&app_clone, synac, id);
+                }
+            });
+        });
+
+        let app_clone = Rc::clone(app);
+        button.connect_button_press_event(move |_, event| {
+            if event.get_button() == 3 {
+                let menu = Menu::new();
 
-                    let author = Label::new(&*synac.state.users[&msg.author].name);
-                    author.set_xalign(0.0);
-                    add_class(&author, "author");
-                    authorbox.add(&author);
+                if public_key(&app_clone.db, addr, id).is_some() {
+                    let verify = MenuItem::new_with_label(&tr("Verify key"));
+                    let app_clone = Rc::clone(&app_clone);
+                    let name = name.clone();
+                    verify.connect_activate(move |_| verify_key(&app_clone, addr, id, &name));
+                    menu.add(&verify);
+                }
 
-                    authorbox.add(&Separator::new(Orientation::Horizontal));
+                let delete = MenuItem::new_with_label(&tr("Delete conversation"));
+                let app_clone = Rc::clone(&app_clone);
+                let name = name.clone();
+                delete.connect_activate(move |_| {
+                    let app_clone = Rc::clone(&app_clone);
+                    confirm(
+                        &app_clone.window,
+                        &fill(&tr("Delete your copy of the conversation with {}? It can't be recovered."), &[&name]),
+                        move || {
+                            app_clone.db.execute(
+                                "DELETE FROM private_messages WHERE server = ? AND user = ?",
+                                &[&addr.to_string(), &(id as i64)]
+                            ).unwrap();
+                            app_clone.connections.execute(addr, |result| {
+                                if let Ok(synac) = result {
+                                    render_private(&app_clone, synac);
+                                }
+                            });
+                        }
+                    );
+                });
+                menu.add(&delete);
 
-                    let mut time = String::with_capacity(32); // just a guess
-                    messages::format_timestamp(&mut time, msg.timestamp);
-                    if let Some(edit) = msg.timestamp_edit {
-                        time.push_str(" (edited ");
-                        messages::format_timestamp(&mut time, edit);
-                        time.push(')');
+                menu.show_all();
+                menu.popup_at_pointer(&**event);
+            }
+            Inhibit(false)
+        });
+        app.direct_messages.add(&button);
+    }
+    app.direct_messages.show_all();
+}
+/// Shows both fingerprints to compare out of band, marking their key as verified if they match.
+/// A key that changed is what gets verified, and replaces the old one if it matches.
+fn verify_key(app: &Rc<App>, addr: SocketAddr, user: usize, name: &str) {
+    let theirs = match public_key(&app.db, addr, user) {
+        Some(key) => key.pending.unwrap_or(key.der),
+        None => return
+    };
+    let ours = match e2e::public_der(&app.private_key) {
+        Ok(der) => der,
+        Err(err) => {
+            eprintln!("error encoding public key: {}", err);
+            return;
+        }
+    };
+    let message = fill(
+        &tr("Compare these with what {0} sees, in person or on a call.\n\n\
+             Your key: {1}\n\
+             {0}'s key: {2}\n\n\
+             Do they match?"),
+        &[name, &e2e::fingerprint(&ours), &e2e::fingerprint(&theirs)]
+    );
+    let app_clone = Rc::clone(app);
+    let name = name.to_string();
+    confirm(&app.window, &message, move || {
+        app_clone.db.execute(
+            "UPDATE public_keys SET key = ?, pending = NULL, verified = 1
+                WHERE server = ? AND user = ? AND (key = ? OR pending = ?)",
+            &[&theirs, &addr.to_string(), &(user as i64), &theirs, &theirs]
+        ).unwrap();
+        // Messages they wrote with the new key were held back until now
+        let opened = open_queued(&app_clone, addr, user, &name);
+
+        if *app_clone.direct.borrow() == Some((addr, user)) {
+            render_key_status(&app_clone, addr, user);
+        }
+        if *app_clone.connections.current_server.lock().unwrap() == Some(addr) {
+            app_clone.connections.execute(addr, |result| {
+                if let Ok(synac) = result {
+                    render_direct(&app_clone, Some(synac));
+                    if !opened.is_empty() {
+                        render_private(&app_clone, synac);
+                    }
+                }
+            });
+        }
+    });
+}
+/// Encrypts and sends a private message, keeping a copy to show in the conversation.
+fn send_private(app: &Rc<App>, addr: SocketAddr, user: usize, text: &str) -> Result<(), String> {
+    let key = match public_key(&app.db, addr, user) {
+        Some(PublicKey { pending: Some(_), .. }) =>
+            return Err(tr("Their key changed. Right-click the conversation and verify the new one before writing.")),
+        Some(key) => key.der,
+        None => return Err(tr("Their key hasn't arrived yet, so this can't be encrypted. Try again once they've been online."))
+    };
+    let text = emoji::replace(text);
+    let timestamp = Utc::now().timestamp();
+    let data = e2e::seal(&text, timestamp, &key, &app.private_key)
+        .map_err(|err| fill(&tr("failed to encrypt message: {}"), &[&err.to_string()]))?;
+
+    let mut sent = false;
+    app.connections.execute(addr, |result| {
+        if let Ok(synac) = result {
+            match synac.session.send(&Packet::PrivateMessage(common::PrivateMessage {
+                text: data,
+                recipient: user
+            })) {
+                Ok(()) => {
+                    store_private(&app.db, addr, user, synac.user, &text, timestamp);
+                    render_private(app, synac);
+                    sent = true;
+                },
+                Err(err) => eprintln!("failed to send packet: {}", err)
+            }
+        }
+    });
+    if sent {
+        Ok(())
+    } else {
+        Err(tr("The message couldn't be sent. Check your connection and try again."))
+    }
+}
+/// Handles a private message, which is either someone's public key or text encrypted with ours.
+/// Called while the connections are locked, so only `synac` may be used.
+pub(crate) fn receive_private(app: &Rc<App>, synac: &mut Synac, author: usize, data: &[u8]) {
+    let addr = synac.addr;
+    let open = *app.direct.borrow() == Some((addr, author));
+    let name = synac.state.users.get(&author).map(|user| user.name.clone()).unwrap_or_else(|| String::from("unknown"));
+
+    match e2e::decode(data) {
+        Ok(e2e::Payload::Key(der)) => {
+            let known = public_key(&app.db, addr, author);
+            match known {
+                Some(ref known) if known.der == der || known.pending.as_ref().map(|pending| &**pending == der) == Some(true) => return,
+                // Whoever relays this could be swapping in their own key,
+                // so the one we have stays in use until the new one is verified
+                Some(_) => {
+                    app.db.execute(
+                        "UPDATE public_keys SET pending = ? WHERE server = ? AND user = ?",
+                        &[&der.to_vec(), &addr.to_string(), &(author as i64)]
+                    ).unwrap();
+                },
+                None => {
+                    app.db.execute(
+                        "INSERT INTO public_keys (server, user, key, verified) VALUES (?, ?, ?, 0)",
+                        &[&addr.to_string(), &(author as i64), &der.to_vec()]
+                    ).unwrap();
+                    // Anything they wrote before it arrived can be checked now
+                    for text in open_queued(app, addr, author, &name) {
+                        notify_private(app, &name, &text);
+                    }
+                }
+            }
+            // They might not have ours either, and this only repeats until both sides have the other's
+            send_public_key(app, synac, author);
+
+            if open && known.is_some() {
+                show_command_output(
+                    app,
+                    &fill(&tr("{}'s key changed. Nothing can be sent to them until you verify the new one."), &[&name]),
+                    true
+                );
+            } else if open {
+                hide_command_output(app);
+            }
+            if open {
+                render_key_status(app, addr, author);
+            }
+        },
+        Ok(e2e::Payload::Sealed(sealed)) => match public_key(&app.db, addr, author) {
+            Some(key) => match e2e::open(sealed, &app.private_key, &key.der) {
+                Ok(message) => {
+                    if store_opened(&app.db, addr, author, &message) {
+                        notify_private(app, &name, &message.text);
+                    } else {
+                        // Someone could be sending an old message of theirs again
+                        eprintln!("dropping replayed private message from {}", name);
                     }
-                    let time = Label::new(&*time);
-                    time.set_margin_right(10);
-                    time.set_hexpand(true);
-                    time.set_xalign(1.0);
-                    add_class(&time, "time");
-                    authorbox.add(&time);
-
-                    msgbox.add(&authorbox);
+                },
+                // The key they changed to can't be trusted yet, so hold on to what it signed
+                Err(E2eError::Forged) if key.pending.as_ref()
+                        .map(|pending| e2e::open(sealed, &app.private_key, pending).is_ok()) == Some(true) => {
+                    queue_private(&app.db, addr, author, sealed);
+                    if open {
+                        show_command_output(
+                            app,
+                            &fill(&tr("{} wrote with their new key. The message shows once you verify it."), &[&name]),
+                            true
+                        );
+                    }
+                },
+                Err(E2eError::Forged) => {
+                    eprintln!("dropping private message from {}: {}", name, E2eError::Forged);
+                    if open {
+                        show_command_output(
+                            app,
+                            &fill(&tr("A private message from {} didn't match their key and was dropped. If their key changed, verify the new one."), &[&name]),
+                            true
+                        );
+                    }
+                },
+                Err(err) => {
+                    eprintln!("error decrypting private message from {}: {}", name, err);
+                    // They probably have an old key of ours
+                    send_public_key(app, synac, author);
+                    if open {
+                        show_command_output(
+                            app,
+                            &fill(&tr("A private message from {} couldn't be decrypted. They've been sent your current key."), &[&name]),
+                            true
+                        );
+                    }
+                }
+            },
+            None => {
+                // Without their key there's no telling who wrote it, so it waits for the key
+                queue_private(&app.db, addr, author, sealed);
+                send_public_key(app, synac, author);
+                if open {
+                    show_command_output(
+                        app,
+                        &fill(&tr("{} wrote before their key arrived. The message shows once it does."), &[&name]),
+                        true
+                    );
                 }
+            }
+        },
+        Err(err) => eprintln!("ignoring private message from {}: {}", name, err)
+    }
+
+    if *app.connections.current_server.lock().unwrap() == Some(addr) {
+        render_direct(app, Some(synac));
+    }
+    if open {
+        render_private(app, synac);
+    }
+}
+pub(crate) fn clear_messages(app: &Rc<App>) {
+    for row in app.message_rows.borrow_mut().drain(..) {
+        if let Some(day) = row.day {
+            app.messages.remove(&day.row);
+        }
+        app.messages.remove(&row.row);
+    }
+}
+/// Throws away every row, for when something all of them depend on changed.
+pub(crate) fn rerender_messages(app: &Rc<App>) {
+    clear_messages(app);
+    if let Some(addr) = *app.connections.current_server.lock().unwrap() {
+        app.connections.execute(addr, |result| {
+            if let Ok(synac) = result {
+                render_messages(app, Some(synac));
+            }
+        });
+    }
+}
+pub(crate) fn render_messages(app: &Rc<App>, synac: Option<&mut Synac>) {
+    let synac = match synac {
+        Some(synac) => synac,
+        None => {
+            clear_messages(app);
+            return;
+        }
+    };
+    let channel = match synac.current_channel {
+        Some(channel) => channel,
+        None => {
+            // A private conversation might be shown instead, which render_private takes care of
+            if app.direct.borrow().is_none() {
+                clear_messages(app);
+            }
+            return;
+        }
+    };
+    let messages = synac.messages.get(channel);
+    let ids: HashSet<usize> = messages.iter().map(|msg| msg.id).collect();
+
+    let mut rows = app.message_rows.borrow_mut();
+
+    // Rows are kept in the same order as the messages, so after dropping
+    // the ones that are gone, whatever doesn't line up must be new.
+    rows.retain(|row| {
+        let keep = ids.contains(&row.id);
+        if !keep {
+            if let Some(ref day) = row.day {
+                app.messages.remove(&day.row);
+            }
+            app.messages.remove(&row.row);
+        }
+        keep
+    });
 
-                let string = Rc::new(String::from_utf8_lossy(&msg.text).into_owned());
-                let output = messages::markdown(&string);
+    let mut last: Option<&common::Message> = None;
+    for (i, msg) in messages.iter().enumerate() {
+        let prev = last.map(|msg| msg.id);
+        let deleted = synac.messages.is_deleted(msg.id);
+        let author = author_name(synac, msg.author);
+        let text = text_hash(&msg.text);
+        let new_day = starts_day(msg.timestamp, last.map(|last| last.timestamp));
+
+        if rows.get(i).map(|row| row.id) != Some(msg.id) {
+            // Day separators are rows too, so go before whatever row is there now
+            let mut position = rows.get(i)
+                .map(|next| next.day.as_ref().map(|day| &day.row).unwrap_or(&next.row).get_index())
+                .unwrap_or(-1);
+
+            let day = if new_day {
+                let day = render_day(app, msg.timestamp);
+                app.messages.insert(&day.row, position);
+                if position >= 0 {
+                    position += 1;
+                }
+                Some(day)
+            } else {
+                None
+            };
 
-                let text = Label::new(None);
-                text.set_line_wrap(true);
-                text.set_line_wrap_mode(WrapMode::WordChar);
-                text.set_text(&string); // In case set_markup fails.
-                text.set_markup(&output);
-                text.set_selectable(true);
-                text.set_xalign(0.0);
+            let row = ListBoxRow::new();
+            row.set_activatable(false);
+            row.set_selectable(false);
+            row.add(&render_message(app, synac, msg, last));
+            row.show_all();
+
+            app.messages.insert(&row, position);
+            rows.insert(i, MessageRow {
+                id: msg.id,
+                prev: prev,
+                edited: msg.timestamp_edit,
+                deleted: deleted,
+                author: author,
+                text: text,
+                day: day,
+                row: row
+            });
+        } else if rows[i].prev != prev || rows[i].edited != msg.timestamp_edit || rows[i].deleted != deleted
+                || rows[i].author != author || rows[i].text != text {
+            // The header depends on the previous message,
+            // so this also catches neighbours of inserts and removals.
+            let row = &mut rows[i];
+            for child in row.row.get_children() {
+                row.row.remove(&child);
+            }
+            row.row.add(&render_message(app, synac, msg, last));
+            row.row.show_all();
+
+            row.prev = prev;
+            row.edited = msg.timestamp_edit;
+            row.deleted = deleted;
+            row.author = author;
+            row.text = text;
+
+            if new_day && row.day.is_none() {
+                let day = render_day(app, msg.timestamp);
+                app.messages.insert(&day.row, row.row.get_index());
+                row.day = Some(day);
+            } else if !new_day {
+                if let Some(day) = row.day.take() {
+                    app.messages.remove(&day.row);
+                }
+            }
+        }
 
-                let app_clone = Rc::clone(app);
-                let msg_id = msg.id;
-                let msg_mine = msg.author == synac.user;
+        last = Some(msg);
+    }
+}
+fn author_name(synac: &Synac, author: usize) -> String {
+    synac.state.users.get(&author)
+        .map(|user| messages::sanitize(user.name.as_bytes()))
+        .unwrap_or_else(|| String::from("unknown"))
+}
+fn text_hash(text: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+fn render_message(app: &Rc<App>, synac: &Synac, msg: &common::Message, last: Option<&common::Message>) -> GtkBox {
+    let addr = synac.addr;
+
+    let msgbox = GtkBox::new(Orientation::Vertical, 2);
+    let author = author_name(synac, msg.author);
+    render_header(app, &msgbox, &author, msg.author, msg.timestamp, msg.timestamp_edit,
+                  last.map(|last| (last.author, last.timestamp)));
+
+    let deleted = synac.messages.is_deleted(msg.id);
+    if deleted {
+        let placeholder = Label::new(&*tr("Message deleted"));
+        placeholder.set_xalign(0.0);
+        add_class(&placeholder, "deleted");
+        msgbox.add(&placeholder);
+
+        // The text was received before it got deleted, so this isn't a secret,
+        // but it's only shown to moderators who asked for it.
+        if !app.reveal_deleted.get() || !can_manage_messages(synac) {
+            return msgbox;
+        }
+    }
 
-                text.connect_populate_popup(move |_, menu| {
-                    menu.add(&SeparatorMenuItem::new());
+    let string = Rc::new(String::from_utf8_lossy(&msg.text).into_owned());
+    let text = render_text(app, &msg.text);
 
-                    let mut has_perms = false;
+    if deleted {
+        add_class(&text, "deleted");
+        msgbox.add(&text);
+        return msgbox;
+    }
 
-                    if msg_mine {
-                        has_perms = true;
+    let app_clone = Rc::clone(app);
+    let msg_id = msg.id;
+    let msg_mine = msg.author == synac.user;
+    let msg_edited = msg.timestamp_edit.is_some();
+    let msg_channel = msg.channel;
+
+    text.connect_populate_popup(move |_, menu| {
+        menu.add(&SeparatorMenuItem::new());
+
+        let blocks = markup::code_blocks(&string);
+        let count = blocks.len();
+        for (i, code) in blocks.into_iter().enumerate() {
+            let label = if count == 1 {
+                tr("Copy _code")
+            } else {
+                fill(&tr("Copy code block {}"), &[&(i+1).to_string()])
+            };
+            let copy = MenuItem::new_with_mnemonic(&label);
+            copy.connect_activate(move |_| {
+                Clipboard::get(&gdk::SELECTION_CLIPBOARD).set_text(&code);
+            });
+            menu.add(&copy);
+        }
 
-                        let edit = MenuItem::new_with_mnemonic("_E_dit message");
+        let links = markup::links(&string);
+        let count = links.len();
+        for link in links {
+            // Not a mnemonic, since URLs tend to contain underscores
+            let copy = if count == 1 {
+                MenuItem::new_with_mnemonic(&tr("Copy _link"))
+            } else {
+                MenuItem::new_with_label(&fill(&tr("Copy link to {}"), &[&link]))
+            };
+            copy.connect_activate(move |_| {
+                Clipboard::get(&gdk::SELECTION_CLIPBOARD).set_text(&link);
+            });
+            menu.add(&copy);
+        }
 
-                        let app_clone = Rc::clone(&app_clone);
-                        let string = Rc::clone(&string);
-                        edit.connect_activate(move |_| {
-                            *app_clone.message_edit_id.borrow_mut() = Some(msg_id);
-                            app_clone.message_edit_input.set_text(&string);
-                            app_clone.message_edit_input.grab_focus();
-                            app_clone.message_edit.set_reveal_child(true);
-                        });
+        let link = MenuItem::new_with_mnemonic(&tr("Copy link to _message"));
 
-                        menu.add(&edit);
-                    } else {
-                        app_clone.connections.execute(addr, |result| {
-                            if result.is_err() { return; }
-                            let synac = result.unwrap();
+        let app_clone2 = Rc::clone(&app_clone);
+        link.connect_activate(move |_| {
+            let link = permalink(&app_clone2, addr, Some(msg_channel), Some(msg_id));
+            Clipboard::get(&gdk::SELECTION_CLIPBOARD).set_text(&link);
+        });
+        menu.add(&link);
 
-                            if synac.current_channel.is_none() { return };
-                            let channel_id = synac.current_channel.unwrap();
+        if msg_edited {
+            let history = MenuItem::new_with_mnemonic(&tr("View edit _history"));
 
-                            if let Some(channel) = synac.state.channels.get(&channel_id) {
-                                if let Some(user) = synac.state.users.get(&synac.user) {
-                                    has_perms = synac::get_mode(channel, user) & common::PERM_MANAGE_MESSAGES
-                                                    == common::PERM_MANAGE_MESSAGES;
-                                }
-                            }
-                        });
-                    }
+            let app_clone = Rc::clone(&app_clone);
+            history.connect_activate(move |_| show_edit_history(&app_clone, addr, msg_id));
 
-                    if has_perms {
-                        let delete = MenuItem::new_with_mnemonic("_D_elete message");
+            menu.add(&history);
+        }
 
-                        let app_clone = Rc::clone(&app_clone);
-                        delete.connect_activate(move |_| {
-                            app_clone.connections.execute(addr, |result| {
-                                if result.is_err() { return; }
-                                let synac = result.unwrap();
+        let mut has_perms = false;
 
-                                let result = synac.session.send(&Packet::MessageDelete(common::MessageDelete {
-                                    id: msg_id
-                                }));
-                                if let Err(err) = result {
-                                    eprintln!("error sending packet: {}", err);
-                                }
-                            });
-                        });
+        if msg_mine {
+            has_perms = true;
 
-                        menu.add(&delete);
+            let edit = MenuItem::new_with_mnemonic(&tr("_E_dit message"));
+
+            let app_clone = Rc::clone(&app_clone);
+            let string = Rc::clone(&string);
+            edit.connect_activate(move |_| {
+                *app_clone.message_edit_id.borrow_mut() = Some(msg_id);
+                app_clone.message_edit_input.set_text(&string);
+                app_clone.message_edit_input.grab_focus();
+                app_clone.message_edit.set_reveal_child(true);
+            });
+
+            menu.add(&edit);
+        } else {
+            app_clone.connections.execute(addr, |result| {
+                if let Ok(synac) = result {
+                    has_perms = can_manage_messages(synac);
+                }
+            });
+        }
+
+        if has_perms {
+            let delete = MenuItem::new_with_mnemonic(&tr("_D_elete message"));
+
+            let app_clone = Rc::clone(&app_clone);
+            delete.connect_activate(move |_| {
+                app_clone.connections.execute(addr, |result| {
+                    if result.is_err() { return; }
+                    let synac = result.unwrap();
+
+                    let result = synac.session.send(&Packet::MessageDelete(common::MessageDelete {
+                        id: msg_id
+                    }));
+                    if let Err(err) = result {
+                        eprintln!("error sending packet: {}", err);
                     }
-                    menu.show_all();
                 });
+            });
 
-                msgbox.add(&text);
-                app.messages.add(&msgbox);
+            menu.add(&delete);
+        }
+        menu.show_all();
+    });
+
+    msgbox.add(&text);
+    msgbox
+}
+/// Adds the day separator and author line a message starts with, when it needs them.
+/// `last` is the author and timestamp of the message before, if any.
+fn render_header(app: &Rc<App>, msgbox: &GtkBox, author: &str, author_id: usize,
+                 timestamp: i64, edit: Option<i64>, last: Option<(usize, i64)>) {
+    let authorbox = GtkBox::new(Orientation::Horizontal, 4);
+    let format = app.time_format.borrow();
+
+    let new_day = starts_day(timestamp, last.map(|(_, last)| last));
+    if new_day
+        || last.map(|(author, _)| author) != Some(author_id)
+        || last.map(|(_, last)| last + 60*5) < Some(timestamp) {
+        if last.is_some() && !new_day {
+            msgbox.add(&Separator::new(Orientation::Vertical));
+        }
 
-                last = Some(msg);
+        let author = Label::new(author);
+        author.set_xalign(0.0);
+        add_class(&author, "author");
+        authorbox.add(&author);
+
+        authorbox.add(&Separator::new(Orientation::Horizontal));
+
+        let mut time = String::with_capacity(32); // just a guess
+        messages::format_time(&mut time, timestamp, &format);
+        if let Some(edit) = edit {
+            let mut edited = String::with_capacity(32);
+            if messages::same_day(timestamp, edit) {
+                messages::format_time(&mut edited, edit, &format);
+            } else {
+                messages::format_timestamp(&mut edited, edit, &format);
             }
+            time.push(' ');
+            time.push_str(&fill(&tr("(edited {})"), &[&edited]));
+        }
+        let time = Label::new(&*time);
+        let mut exact = String::with_capacity(26);
+        messages::format_exact(&mut exact, timestamp);
+        time.set_tooltip_text(&*exact);
+        time.set_margin_right(10);
+        time.set_hexpand(true);
+        time.set_xalign(1.0);
+        add_class(&time, "time");
+        authorbox.add(&time);
+
+        msgbox.add(&authorbox);
+    }
+}
+/// Returns true if a message needs a day separator above it.
+fn starts_day(timestamp: i64, last: Option<i64>) -> bool {
+    last.map(|last| messages::same_day(last, timestamp)) != Some(true)
+}
+fn day_label(app: &Rc<App>, timestamp: i64) -> String {
+    let mut date = String::with_capacity(32); // just a guess
+    messages::format_date(&mut date, timestamp, &app.time_format.borrow());
+    date
+}
+/// Makes the separator row above the first message of a day.
+fn render_day(app: &Rc<App>, timestamp: i64) -> DayRow {
+    let daybox = GtkBox::new(Orientation::Horizontal, 10);
+
+    let before = Separator::new(Orientation::Horizontal);
+    before.set_hexpand(true);
+    before.set_valign(Align::Center);
+    daybox.add(&before);
+
+    let label = Label::new(&*day_label(app, timestamp));
+    add_class(&label, "day");
+    daybox.add(&label);
+
+    let after = Separator::new(Orientation::Horizontal);
+    after.set_hexpand(true);
+    after.set_valign(Align::Center);
+    daybox.add(&after);
+
+    let row = ListBoxRow::new();
+    row.set_activatable(false);
+    row.set_selectable(false);
+    row.add(&daybox);
+    row.show_all();
+
+    DayRow {
+        timestamp: timestamp,
+        label: label,
+        row: row
+    }
+}
+/// Updates relative dates like "Today", which go stale at midnight.
+pub(crate) fn refresh_days(app: &Rc<App>) {
+    for row in &*app.message_rows.borrow() {
+        if let Some(ref day) = row.day {
+            day.label.set_text(&day_label(app, day.timestamp));
+        }
+    }
+}
+/// Renders a message's markdown, with links going through `open_link`.
+fn render_text(app: &Rc<App>, text: &[u8]) -> Label {
+    let safe = messages::sanitize(text);
+    let output = markup::render(&safe, app.theme);
+
+    let label = Label::new(None);
+    label.set_line_wrap(true);
+    label.set_line_wrap_mode(WrapMode::WordChar);
+    label.set_text(&safe); // In case set_markup fails.
+    label.set_markup(&output);
+    label.set_selectable(true);
+    label.set_xalign(0.0);
+
+    let app_clone = Rc::clone(app);
+    label.connect_activate_link(move |_, uri| {
+        open_link(&app_clone, uri);
+        Inhibit(true)
+    });
+    label
+}
+/// Shows every version of a message this client has seen, oldest first.
+fn show_edit_history(app: &Rc<App>, addr: SocketAddr, id: usize) {
+    let mut versions = Vec::new();
+    app.connections.execute(addr, |result| {
+        if result.is_err() { return; }
+        let synac = result.unwrap();
+
+        versions.extend(synac.messages.history(id).iter().cloned());
+        if let Some(msg) = synac.messages.find(id) {
+            versions.push(msg.clone());
         }
+    });
+    if versions.is_empty() {
+        return;
+    }
+
+    let dialog = Dialog::new_with_buttons(
+        Some(&*tr("Edit history")),
+        Some(&app.window),
+        DialogFlags::MODAL,
+        &[(&tr("_Close"), ResponseType::Close.into())]
+    );
+    dialog.set_default_size(500, 400);
+
+    let list = GtkBox::new(Orientation::Vertical, 10);
+    list.set_property_margin(10);
+
+    let format = app.time_format.borrow();
+    if versions[0].timestamp_edit.is_some() {
+        let note = Label::new(&*tr("Earlier versions were edited before this client saw them."));
+        note.set_line_wrap(true);
+        note.set_xalign(0.0);
+        add_class(&note, "time");
+        list.add(&note);
     }
-    app.messages.show_all();
-    app.messages.queue_draw();
+    for (i, version) in versions.iter().enumerate() {
+        let mut time = String::with_capacity(32);
+        messages::format_timestamp(&mut time, version.timestamp_edit.unwrap_or(version.timestamp), &format);
+        let header = if version.timestamp_edit.is_none() {
+            fill(&tr("Original, {}"), &[&time])
+        } else if i + 1 == versions.len() {
+            fill(&tr("Current, edited {}"), &[&time])
+        } else {
+            fill(&tr("Edited {}"), &[&time])
+        };
+        let header = Label::new(&*header);
+        header.set_xalign(0.0);
+        add_class(&header, "author");
+        list.add(&header);
+
+        let safe = messages::sanitize(&version.text);
+        let text = Label::new(None);
+        text.set_line_wrap(true);
+        text.set_line_wrap_mode(WrapMode::WordChar);
+        text.set_text(&safe);
+        text.set_markup(&markup::render(&safe, app.theme));
+        text.set_selectable(true);
+        text.set_xalign(0.0);
+        list.add(&text);
+    }
+
+    let scroll = ScrolledWindow::new(None, None);
+    scroll.set_policy(PolicyType::Never, PolicyType::Automatic);
+    scroll.set_vexpand(true);
+    scroll.add(&list);
+    dialog.get_content_area().add(&scroll);
+
+    dialog.connect_response(|dialog, _| dialog.destroy());
+    dialog.show_all();
 }
 pub(crate) fn render_users(app: &Rc<App>, synac: Option<&mut Synac>) {
     for child in app.users.get_children() {
         app.users.remove(&child);
     }
+    app.typing_users.borrow_mut().clear();
     if let Some(synac) = synac {
         if let Some(channel) = synac.current_channel.and_then(|id| synac.state.channels.get(&id)) {
             let addr = synac.addr;
@@ -595,8 +2936,13 @@ pub(crate) fn render_users(app: &Rc<App>, synac: Option<&mut Synac>) {
                 let label = Label::new(&*user.name);
                 label.set_xalign(0.0);
                 label.set_margin_left(10);
+                let content = GtkBox::new(Orientation::Horizontal, 4);
+                content.add(&label);
+                let indicator = typing_indicator();
+                content.add(&indicator);
+                app.typing_users.borrow_mut().insert(user.id, indicator);
                 let event = EventBox::new();
-                event.add(&label);
+                event.add(&content);
 
                 let user_id = user.id;
                 let app_clone = Rc::clone(app);
@@ -610,6 +2956,7 @@ pub(crate) fn render_users(app: &Rc<App>, synac: Option<&mut Synac>) {
 
                     let mut other_admin = None;
                     let mut other_ban = None;
+                    let mut other_bot = None;
 
                     app_clone.connections.execute(addr, |result| {
                         if result.is_err() { return; }
@@ -630,12 +2977,30 @@ pub(crate) fn render_users(app: &Rc<App>, synac: Option<&mut Synac>) {
                         let other = synac.state.users.get(&user_id);
                         other_admin = other.map(|user| user.admin);
                         other_ban   = other.map(|user| user.ban);
+                        other_bot   = other.map(|user| user.bot);
                     });
 
                     let menu = Menu::new();
 
+                    // Bots couldn't decrypt it anyway
+                    if user_id != id && other_bot == Some(false) {
+                        let message = MenuItem::new_with_label(&tr("Send private message"));
+
+                        let app_clone = Rc::clone(&app_clone);
+                        message.connect_activate(move |_| {
+                            app_clone.connections.execute(addr, |result| {
+                                if let Ok(synac) = result {
+                                    open_direct(&app_clone, synac, user_id);
+                                }
+                            });
+                            app_clone.message_composer.grab_focus();
+                        });
+
+                        menu.add(&message);
+                    }
+
                     if mode & common::PERM_MANAGE_MODES == common::PERM_MANAGE_MODES {
-                        let edit_mode = MenuItem::new_with_label("Edit mode");
+                        let edit_mode = MenuItem::new_with_label(&tr("Edit mode"));
 
                         let app_clone = Rc::clone(&app_clone);
                         edit_mode.connect_activate(move |_| {
@@ -669,11 +3034,11 @@ pub(crate) fn render_users(app: &Rc<App>, synac: Option<&mut Synac>) {
                     }
                     if let Some(other_admin) = other_admin {
                         if admin && user_id != id {
-                            let toggle_admin = MenuItem::new_with_label(if other_admin {
+                            let toggle_admin = MenuItem::new_with_label(&tr(if other_admin {
                                 "Demote admin"
                             } else {
                                 "Promote admin"
-                            });
+                            }));
 
                             let app_clone1 = Rc::clone(&app_clone);
                             toggle_admin.connect_activate(move |_| {
@@ -697,21 +3062,21 @@ pub(crate) fn render_users(app: &Rc<App>, synac: Option<&mut Synac>) {
 
                             if let Some(other_ban) = other_ban {
                                 if !other_admin {
-                                    let toggle_ban = MenuItem::new_with_label(if other_ban {
+                                    let toggle_ban = MenuItem::new_with_label(&tr(if other_ban {
                                         "Unban user"
                                     } else {
                                         "Ban user"
-                                    });
+                                    }));
 
                                     let app_clone2 = Rc::clone(&app_clone);
                                     toggle_ban.connect_activate(move |_| {
                                         let app_clone = Rc::clone(&app_clone2);
-                                        let text = if other_ban {
+                                        let text = tr(if other_ban {
                                             "Are you sure you want to unban this user?"
                                         } else {
                                             "Are you sure you want to ban this user?"
-                                        };
-                                        confirm(&app_clone2.window, text, move || {
+                                        });
+                                        confirm(&app_clone2.window, &text, move || {
                                             app_clone.connections.execute(addr, |result| {
                                                 if result.is_err() { return; }
                                                 let synac = result.unwrap();
@@ -747,7 +3112,7 @@ pub(crate) fn render_users(app: &Rc<App>, synac: Option<&mut Synac>) {
             let mut users: Vec<_> = synac.state.users.values().collect();
             users.sort_by_key(|user| &user.name);
 
-            let label = Label::new("This channel:");
+            let label = Label::new(&*tr("This channel:"));
             add_class(&label, "bold");
             label.set_xalign(0.0);
             app.users.add(&label);
@@ -758,7 +3123,7 @@ pub(crate) fn render_users(app: &Rc<App>, synac: Option<&mut Synac>) {
                 !user.ban && synac::get_mode(channel, user) & common::PERM_READ == common::PERM_READ
             }).for_each(&draw);
 
-            let label = Label::new("Other:");
+            let label = Label::new(&*tr("Other:"));
             add_class(&label, "bold");
             label.set_xalign(0.0);
             app.users.add(&label);
@@ -767,14 +3132,49 @@ pub(crate) fn render_users(app: &Rc<App>, synac: Option<&mut Synac>) {
                 !user.ban && synac::get_mode(channel, user) & common::PERM_READ != common::PERM_READ
             }).for_each(&draw);
 
-            let label = Label::new("Banned:");
+            let label = Label::new(&*tr("Banned:"));
             add_class(&label, "bold");
             label.set_xalign(0.0);
             app.users.add(&label);
 
             users.iter().cloned().filter(|user| user.ban).for_each(&draw);
         }
+        render_typing(app, synac);
     }
     app.users.show_all();
     app.users.queue_draw();
 }
+/// Makes the pencil shown next to a channel or user while someone's typing.
+fn typing_indicator() -> Revealer {
+    let label = Label::new("✎");
+    add_class(&label, "typing");
+
+    let indicator = Revealer::new();
+    indicator.set_transition_type(RevealerTransitionType::Crossfade);
+    indicator.add(&label);
+    indicator
+}
+/// Reveals the typing indicators of other channels, and of users in this one,
+/// without rebuilding either list.
+pub(crate) fn render_typing(app: &Rc<App>, synac: &Synac) {
+    let mut channels = HashSet::new();
+    let mut users = HashSet::new();
+    for &(author, channel) in synac.typing.people() {
+        if author == synac.user {
+            continue;
+        }
+        // The current channel already says who's typing below the messages
+        if synac.current_channel == Some(channel) {
+            users.insert(author);
+        } else {
+            channels.insert(channel);
+        }
+    }
+
+    for (id, indicator) in &*app.typing_channels.borrow() {
+        indicator.set_reveal_child(channels.contains(id));
+    }
+    for (id, indicator) in &*app.typing_users.borrow() {
+        indicator.set_reveal_child(users.contains(id));
+    }
+}
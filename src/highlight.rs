@@ -0,0 +1,257 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Theme {
+    Dark,
+    Light
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Plain,
+    Comment,
+    Keyword,
+    Number,
+    String,
+    Type,
+    Variable
+}
+impl Kind {
+    pub fn color(self, theme: Theme) -> Option<&'static str> {
+        // Colors borrowed from the One Dark/One Light palettes
+        let dark = theme == Theme::Dark;
+        match self {
+            Kind::Plain => None,
+            Kind::Comment  => Some(if dark { "#7F848E" } else { "#A0A1A7" }),
+            Kind::Keyword  => Some(if dark { "#C678DD" } else { "#A626A4" }),
+            Kind::Number   => Some(if dark { "#D19A66" } else { "#986801" }),
+            Kind::String   => Some(if dark { "#98C379" } else { "#50A14F" }),
+            Kind::Type     => Some(if dark { "#E5C07B" } else { "#C18401" }),
+            Kind::Variable => Some(if dark { "#61AFEF" } else { "#4078F2" })
+        }
+    }
+}
+
+pub struct Grammar {
+    pub names: &'static [&'static str],
+    pub keywords: &'static [&'static str],
+    pub types: &'static [&'static str],
+    /// Treat capitalized identifiers as types
+    pub capitalized_types: bool,
+    pub line_comments: &'static [&'static str],
+    pub block_comment: Option<(&'static str, &'static str)>,
+    pub strings: &'static [char],
+    /// Highlight $name and ${name}, like shells do
+    pub variables: bool
+}
+
+pub const GRAMMARS: &[Grammar] = &[
+    Grammar {
+        names: &["rust", "rs"],
+        keywords: &[
+            "as", "break", "const", "continue", "crate", "else", "enum", "extern", "false", "fn", "for",
+            "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return",
+            "self", "Self", "static", "struct", "super", "trait", "true", "type", "unsafe", "use",
+            "where", "while"
+        ],
+        types: &[
+            "bool", "char", "str", "u8", "u16", "u32", "u64", "u128", "usize",
+            "i8", "i16", "i32", "i64", "i128", "isize", "f32", "f64"
+        ],
+        capitalized_types: true,
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &['"'],
+        variables: false
+    },
+    Grammar {
+        names: &["sh", "bash", "shell", "zsh", "console"],
+        keywords: &[
+            "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+            "in", "local", "return", "then", "until", "while"
+        ],
+        types: &[
+            "cd", "echo", "exit", "printf", "read", "set", "shift", "source", "sudo", "test", "unset"
+        ],
+        capitalized_types: false,
+        line_comments: &["#"],
+        block_comment: None,
+        strings: &['"', '\''],
+        variables: true
+    },
+    Grammar {
+        names: &["python", "py"],
+        keywords: &[
+            "and", "as", "assert", "break", "class", "continue", "def", "del", "elif", "else",
+            "except", "False", "finally", "for", "from", "global", "if", "import", "in", "is",
+            "lambda", "None", "nonlocal", "not", "or", "pass", "raise", "return", "True", "try",
+            "while", "with", "yield"
+        ],
+        types: &["bool", "bytes", "dict", "float", "int", "list", "object", "set", "str", "tuple"],
+        capitalized_types: true,
+        line_comments: &["#"],
+        block_comment: None,
+        strings: &['"', '\''],
+        variables: false
+    },
+    Grammar {
+        names: &["javascript", "js", "json", "typescript", "ts"],
+        keywords: &[
+            "async", "await", "break", "case", "catch", "class", "const", "continue", "default",
+            "delete", "do", "else", "export", "extends", "false", "finally", "for", "function", "if",
+            "import", "in", "instanceof", "let", "new", "null", "return", "switch", "this", "throw",
+            "true", "try", "typeof", "undefined", "var", "while", "yield"
+        ],
+        types: &[],
+        capitalized_types: true,
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &['"', '\'', '`'],
+        variables: false
+    },
+    Grammar {
+        names: &["c", "h", "cpp", "c++", "cxx", "hpp"],
+        keywords: &[
+            "break", "case", "class", "const", "continue", "default", "do", "else", "enum", "extern",
+            "for", "goto", "if", "namespace", "return", "sizeof", "static", "struct", "switch",
+            "template", "typedef", "union", "while"
+        ],
+        types: &[
+            "auto", "bool", "char", "double", "float", "int", "long", "short", "signed", "unsigned",
+            "void", "size_t"
+        ],
+        capitalized_types: false,
+        line_comments: &["//", "#"],
+        block_comment: Some(("/*", "*/")),
+        strings: &['"', '\''],
+        variables: false
+    }
+];
+
+/// Finds a grammar by the info string of a fenced code block, such as "rust" or "sh".
+pub fn find(info: &str) -> Option<&'static Grammar> {
+    let name = info.split_whitespace().next().unwrap_or("").to_lowercase();
+    GRAMMARS.iter().find(|grammar| grammar.names.contains(&&*name))
+}
+
+/// Splits code into highlighted pieces, which together make up the whole input.
+pub fn tokens<'a>(code: &'a str, grammar: &Grammar) -> Vec<(Kind, &'a str)> {
+    let mut tokens: Vec<(Kind, &'a str)> = Vec::new();
+    let mut start = 0;
+
+    while start < code.len() {
+        let (kind, len) = next(&code[start..], grammar);
+        let end = start + len;
+
+        let merge = match tokens.last() {
+            Some(&(last, _)) => last == kind && kind == Kind::Plain,
+            None => false
+        };
+        if merge {
+            let (_, text) = tokens.pop().unwrap();
+            let begin = text.as_ptr() as usize - code.as_ptr() as usize;
+            tokens.push((kind, &code[begin..end]));
+        } else {
+            tokens.push((kind, &code[start..end]));
+        }
+        start = end;
+    }
+
+    tokens
+}
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+fn take_while<F: Fn(char) -> bool>(input: &str, skip: usize, f: F) -> usize {
+    input[skip..].char_indices()
+        .find(|&(_, c)| !f(c))
+        .map(|(i, _)| skip + i)
+        .unwrap_or(input.len())
+}
+fn next(rest: &str, grammar: &Grammar) -> (Kind, usize) {
+    for comment in grammar.line_comments {
+        if rest.starts_with(comment) {
+            return (Kind::Comment, rest.find('\n').unwrap_or(rest.len()));
+        }
+    }
+    if let Some((start, end)) = grammar.block_comment {
+        if rest.starts_with(start) {
+            let len = rest[start.len()..].find(end)
+                .map(|i| start.len() + i + end.len())
+                .unwrap_or(rest.len());
+            return (Kind::Comment, len);
+        }
+    }
+
+    let c = rest.chars().next().unwrap();
+
+    if grammar.strings.contains(&c) {
+        let mut escape = false;
+        for (i, c2) in rest.char_indices().skip(1) {
+            if escape {
+                escape = false;
+            } else if c2 == '\\' {
+                escape = true;
+            } else if c2 == c {
+                return (Kind::String, i + c2.len_utf8());
+            }
+        }
+        return (Kind::String, rest.len());
+    }
+    if grammar.variables && c == '$' {
+        if rest[1..].starts_with('{') {
+            return (Kind::Variable, rest.find('}').map(|i| i + 1).unwrap_or(rest.len()));
+        }
+        let len = take_while(rest, 1, is_ident);
+        if len > 1 {
+            return (Kind::Variable, len);
+        }
+    }
+    if c.is_digit(10) {
+        return (Kind::Number, take_while(rest, 0, |c| is_ident(c) || c == '.'));
+    }
+    if is_ident(c) {
+        let len = take_while(rest, 0, is_ident);
+        let word = &rest[..len];
+
+        let kind = if grammar.keywords.contains(&word) {
+            Kind::Keyword
+        } else if grammar.types.contains(&word)
+                || (grammar.capitalized_types && word.chars().next().map(|c| c.is_uppercase()).unwrap_or(false)) {
+            Kind::Type
+        } else {
+            Kind::Plain
+        };
+        return (kind, len);
+    }
+
+    (Kind::Plain, c.len_utf8())
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    let rust = find("rust").unwrap();
+    assert_eq!(tokens("let x = \"a\\\"b\"; // hi", rust), &[
+        (Kind::Keyword, "let"),
+        (Kind::Plain, " x = "),
+        (Kind::String, "\"a\\\"b\""),
+        (Kind::Plain, "; "),
+        (Kind::Comment, "// hi")
+    ]);
+    assert_eq!(tokens("Vec<u8> 42", rust), &[
+        (Kind::Type, "Vec"),
+        (Kind::Plain, "<"),
+        (Kind::Type, "u8"),
+        (Kind::Plain, "> "),
+        (Kind::Number, "42")
+    ]);
+
+    let sh = find("Bash").unwrap();
+    assert_eq!(tokens("echo $HOME ${x}", sh), &[
+        (Kind::Type, "echo"),
+        (Kind::Plain, " "),
+        (Kind::Variable, "$HOME"),
+        (Kind::Plain, " "),
+        (Kind::Variable, "${x}")
+    ]);
+    assert!(find("brainfuck").is_none());
+}
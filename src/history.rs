@@ -0,0 +1,103 @@
+/// Maximum number of sent messages to remember per channel.
+pub const MAX: usize = 100;
+
+/// What was sent in a channel, oldest first, and where in it
+/// Ctrl+Up/Ctrl+Down currently are.
+#[derive(Debug, Default)]
+pub struct History {
+    entries: Vec<String>,
+    position: Option<usize>,
+    stash: String
+}
+impl History {
+    pub fn new(entries: Vec<String>) -> Self {
+        History {
+            entries: entries,
+            position: None,
+            stash: String::new()
+        }
+    }
+    pub fn get(&self, i: usize) -> Option<&str> {
+        self.entries.get(i).map(|entry| &**entry)
+    }
+    /// Remembers a sent message. Returns false if it was the same as the last one.
+    pub fn push(&mut self, text: &str) -> bool {
+        self.reset();
+        if self.entries.last().map(|last| last == text).unwrap_or(false) {
+            return false;
+        }
+        self.entries.push(text.to_string());
+        if self.entries.len() > MAX {
+            let excess = self.entries.len() - MAX;
+            self.entries.drain(..excess);
+        }
+        true
+    }
+    /// Stops navigating, so the next step starts from the newest entry again.
+    pub fn reset(&mut self) {
+        self.position = None;
+        self.stash.clear();
+    }
+    /// Steps back to an older entry. `current` is what's in the composer,
+    /// which is given back once stepping forward past the newest entry.
+    pub fn older(&mut self, current: &str) -> Option<&str> {
+        let position = match self.position {
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.stash = current.to_string();
+                self.entries.len() - 1
+            },
+            Some(0) => return None,
+            Some(i) => i - 1
+        };
+        self.position = Some(position);
+        Some(&self.entries[position])
+    }
+    /// Steps forward to a newer entry, or back to what was being typed.
+    pub fn newer(&mut self) -> Option<&str> {
+        let position = self.position?;
+        if position + 1 < self.entries.len() {
+            self.position = Some(position + 1);
+            Some(&self.entries[position + 1])
+        } else {
+            self.position = None;
+            Some(&self.stash)
+        }
+    }
+    /// Finds the newest entry containing `query`, only looking before `before` if set.
+    pub fn search(&self, query: &str, before: Option<usize>) -> Option<usize> {
+        if query.is_empty() {
+            return None;
+        }
+        let end = before.unwrap_or(self.entries.len()).min(self.entries.len());
+        self.entries[..end].iter().rposition(|entry| entry.contains(query))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    let mut history = History::new(vec![String::from("hi"), String::from("ls -l")]);
+    assert!(!history.push("ls -l"));
+    assert!(history.push("how are you"));
+
+    assert_eq!(history.older("typing"), Some("how are you"));
+    assert_eq!(history.older("ignored"), Some("ls -l"));
+    assert_eq!(history.older(""), Some("hi"));
+    assert_eq!(history.older(""), None);
+    assert_eq!(history.newer(), Some("ls -l"));
+    assert_eq!(history.newer(), Some("how are you"));
+    assert_eq!(history.newer(), Some("typing"));
+    assert_eq!(history.newer(), None);
+
+    assert_eq!(history.search("l", None), Some(1));
+    assert_eq!(history.search("h", Some(2)), Some(0));
+    assert_eq!(history.search("h", Some(0)), None);
+    assert_eq!(history.search("", None), None);
+
+    for i in 0..MAX {
+        history.push(&i.to_string());
+    }
+    assert_eq!(history.get(0), Some("0"));
+    assert_eq!(history.get(MAX), None);
+}
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::env;
+
+/// Every catalog compiled into the client, by language code.
+const CATALOGS: &[(&str, &str)] = &[
+    ("de", include_str!("po/de.po"))
+];
+
+pub struct Catalog {
+    plural: fn(u64) -> usize,
+    strings: HashMap<String, Vec<String>>
+}
+impl Catalog {
+    pub fn new(language: &str, source: &str) -> Self {
+        Catalog {
+            plural: plural_rule(language),
+            strings: parse(source)
+        }
+    }
+    pub fn get(&self, msgid: &str) -> Option<&str> {
+        self.strings.get(msgid).and_then(|forms| forms.first()).map(|form| &**form)
+    }
+    pub fn get_plural(&self, msgid: &str, n: u64) -> Option<&str> {
+        self.strings.get(msgid).and_then(|forms| forms.get((self.plural)(n))).map(|form| &**form)
+    }
+}
+
+thread_local! {
+    static CATALOG: Option<Catalog> = detect();
+}
+
+/// Lists the languages the user asked for, most preferred first,
+/// following the same order of variables as gettext.
+pub fn languages() -> Vec<String> {
+    let mut languages = Vec::new();
+
+    if let Ok(list) = env::var("LANGUAGE") {
+        languages.extend(list.split(':').filter(|lang| !lang.is_empty()).map(String::from));
+    }
+    for var in &["LC_ALL", "LC_MESSAGES", "LANG"] {
+        if let Ok(lang) = env::var(var) {
+            if !lang.is_empty() {
+                languages.push(lang);
+                break;
+            }
+        }
+    }
+
+    languages
+}
+fn detect() -> Option<Catalog> {
+    for lang in languages() {
+        // de_DE.UTF-8@euro => de_DE
+        let lang = lang.split(|c: char| c == '.' || c == '@').next().unwrap_or("");
+        if lang == "C" || lang == "POSIX" {
+            return None;
+        }
+        let short = lang.split('_').next().unwrap_or("");
+
+        for &(code, source) in CATALOGS {
+            if code == lang || code == short {
+                return Some(Catalog::new(code, source));
+            }
+        }
+    }
+    None
+}
+fn plural_rule(language: &str) -> fn(u64) -> usize {
+    fn germanic(n: u64) -> usize { if n == 1 { 0 } else { 1 } }
+    fn romance(n: u64) -> usize { if n > 1 { 1 } else { 0 } }
+    fn slavic(n: u64) -> usize {
+        if n % 10 == 1 && n % 100 != 11 { 0 }
+        else if n % 10 >= 2 && n % 10 <= 4 && (n % 100 < 10 || n % 100 >= 20) { 1 }
+        else { 2 }
+    }
+
+    match language {
+        "fr" | "pt_BR" => romance,
+        "ru" | "uk" | "sr" | "hr" | "bs" => slavic,
+        _ => germanic
+    }
+}
+
+/// Parses the subset of the gettext PO format we use:
+/// msgid, msgid_plural, msgstr and msgstr[n], with continuation lines.
+pub fn parse(source: &str) -> HashMap<String, Vec<String>> {
+    let mut strings = HashMap::new();
+
+    let mut msgid = String::new();
+    let mut forms: Vec<String> = Vec::new();
+    let mut current: Option<usize> = None; // None = msgid, Some(n) = msgstr[n]
+    let mut plural = false;
+
+    {
+        let mut finish = |msgid: &mut String, forms: &mut Vec<String>| {
+            if !msgid.is_empty() && !forms.is_empty() && forms.iter().all(|form| !form.is_empty()) {
+                strings.insert(msgid.clone(), forms.clone());
+            }
+            msgid.clear();
+            forms.clear();
+        };
+
+        for line in source.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (keyword, rest) = match line.find('"') {
+                Some(i) => (line[..i].trim(), &line[i..]),
+                None => continue
+            };
+            let value = unescape(rest);
+
+            match keyword {
+                "msgid" => {
+                    finish(&mut msgid, &mut forms);
+                    msgid = value;
+                    current = None;
+                    plural = false;
+                },
+                "msgid_plural" => plural = true,
+                "msgstr" => {
+                    forms.push(value);
+                    current = Some(forms.len() - 1);
+                },
+                _ if keyword.starts_with("msgstr[") => {
+                    forms.push(value);
+                    current = Some(forms.len() - 1);
+                },
+                "" => match current {
+                    None if !plural => msgid.push_str(&value),
+                    None => (),
+                    Some(i) => forms[i].push_str(&value)
+                },
+                _ => ()
+            }
+        }
+        finish(&mut msgid, &mut forms);
+    }
+
+    strings
+}
+fn unescape(quoted: &str) -> String {
+    let inner = quoted.trim();
+    let inner = if inner.len() >= 2 { &inner[1..inner.len()-1] } else { "" };
+
+    let mut output = String::with_capacity(inner.len());
+    let mut escape = false;
+    for c in inner.chars() {
+        if escape {
+            escape = false;
+            output.push(match c {
+                'n' => '\n',
+                't' => '\t',
+                c => c
+            });
+        } else if c == '\\' {
+            escape = true;
+        } else {
+            output.push(c);
+        }
+    }
+    output
+}
+
+/// Translates `msgid`, falling back to it if there is no translation.
+pub fn tr(msgid: &str) -> String {
+    CATALOG.with(|catalog| {
+        catalog.as_ref()
+            .and_then(|catalog| catalog.get(msgid))
+            .unwrap_or(msgid)
+            .to_string()
+    })
+}
+/// Translates a string that depends on a count `n`.
+/// The English singular is used as msgid.
+pub fn trn(singular: &str, plural: &str, n: u64) -> String {
+    CATALOG.with(|catalog| {
+        catalog.as_ref()
+            .and_then(|catalog| catalog.get_plural(singular, n))
+            .unwrap_or(if n == 1 { singular } else { plural })
+            .to_string()
+    })
+}
+/// Fills in `{}` (in order) or `{0}`, `{1}`... (by index),
+/// since translations may need to reorder arguments.
+pub fn fill(template: &str, args: &[&str]) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut next = 0;
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match rest.find('}') {
+            Some(end) => end,
+            None => break
+        };
+        let index = if end == 1 {
+            next += 1;
+            Some(next - 1)
+        } else {
+            rest[1..end].parse::<usize>().ok()
+        };
+        match index.and_then(|i| args.get(i)) {
+            Some(arg) => output.push_str(arg),
+            None => output.push_str(&rest[..end+1])
+        }
+        rest = &rest[end+1..];
+    }
+    output.push_str(rest);
+
+    output
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    let catalog = Catalog::new("de", r#"
+msgid ""
+msgstr ""
+"Content-Type: text/plain; charset=UTF-8\n"
+
+# A comment
+msgid "Hello"
+msgstr "Hallo"
+
+msgid "Imported {} server."
+msgid_plural "Imported {} servers."
+msgstr[0] "{} Server "
+"importiert."
+msgstr[1] "{} Server importiert."
+
+msgid "Untranslated"
+msgstr ""
+"#);
+    assert_eq!(catalog.get("Hello"), Some("Hallo"));
+    assert_eq!(catalog.get(""), None);
+    assert_eq!(catalog.get("Untranslated"), None);
+    assert_eq!(catalog.get_plural("Imported {} server.", 1), Some("{} Server importiert."));
+    assert_eq!(catalog.get_plural("Imported {} server.", 2), Some("{} Server importiert."));
+
+    assert_eq!(plural_rule("ru")(1), 0);
+    assert_eq!(plural_rule("ru")(3), 1);
+    assert_eq!(plural_rule("ru")(11), 2);
+
+    assert_eq!(fill("{} and {} are typing", &["a", "b"]), "a and b are typing");
+    assert_eq!(fill("{1}, {0}.", &["a", "b"]), "b, a.");
+    assert_eq!(fill("{x} {5} {", &["a"]), "{x} {5} {");
+}
@@ -0,0 +1,86 @@
+use chrono::prelude::*;
+use messages;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+pub const DEFAULT_FORMAT: &str = "[%H:%M:%S] <{author}> {text}";
+
+pub struct Logger {
+    files: HashMap<(SocketAddr, usize), (PathBuf, File)>,
+    format: String,
+    root: PathBuf
+}
+impl Logger {
+    pub fn new(root: PathBuf, format: String) -> Self {
+        Logger {
+            files: HashMap::new(),
+            format: if messages::valid_strftime(&format) { format } else { String::from(DEFAULT_FORMAT) },
+            root: root
+        }
+    }
+    pub fn log(&mut self, addr: SocketAddr, channel: usize, channel_name: &str, timestamp: i64, author: &str, text: &str)
+        -> io::Result<()>
+    {
+        let time = Utc.timestamp(timestamp, 0).with_timezone(&Local);
+        let template = time.format(&self.format).to_string();
+
+        let mut output = String::with_capacity(template.len() + text.len());
+        for line in text.lines() {
+            expand(&mut output, &template, &[("{author}", author), ("{channel}", channel_name), ("{text}", line)]);
+            output.push('\n');
+        }
+
+        let mut path = self.root.join(sanitize(&addr.to_string()));
+        path.push(sanitize(channel_name));
+        path.push(format!("{}.log", time.format("%Y-%m-%d")));
+
+        self.file(addr, channel, path)?.write_all(output.as_bytes())
+    }
+    fn file(&mut self, addr: SocketAddr, channel: usize, path: PathBuf) -> io::Result<&mut File> {
+        // Channels get a new file each day (or when renamed),
+        // so only reopen when the path actually changed.
+        let key = (addr, channel);
+        let stale = match self.files.get(&key) {
+            Some(&(ref open, _)) => *open != path,
+            None => true
+        };
+        if stale {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            self.files.insert(key, (path, file));
+        }
+        Ok(&mut self.files.get_mut(&key).unwrap().1)
+    }
+}
+
+fn expand(output: &mut String, template: &str, replacements: &[(&str, &str)]) {
+    let mut rest = template;
+    'outer: while let Some(i) = rest.find('{') {
+        output.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        for &(key, value) in replacements {
+            if rest.starts_with(key) {
+                output.push_str(value);
+                rest = &rest[key.len()..];
+                continue 'outer;
+            }
+        }
+        output.push('{');
+        rest = &rest[1..];
+    }
+    output.push_str(rest);
+}
+fn sanitize(input: &str) -> String {
+    input.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '\0' => '_',
+            c => c
+        })
+        .collect()
+}
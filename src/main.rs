@@ -3,16 +3,29 @@ extern crate chrono;
 extern crate gdk;
 extern crate gtk;
 extern crate notify_rust;
+extern crate openssl;
 extern crate pango;
 extern crate pulldown_cmark;
 extern crate rusqlite;
 extern crate synac;
 extern crate xdg;
 
+mod alias;
+mod commands;
+mod completion;
+mod config;
 mod connections;
+mod e2e;
+mod emoji;
 mod functions;
+mod highlight;
+mod history;
+mod i18n;
+mod logger;
+mod markup;
 mod messages;
 mod parser;
+mod permalink;
 mod typing;
 
 use gtk::{
@@ -21,50 +34,82 @@ use gtk::{
     Button,
     ButtonsType,
     CheckButton,
+    Clipboard,
+    ComboBoxText,
     CssProvider,
     Dialog,
     DialogFlags,
     Entry,
     EventBox,
+    FileChooserAction,
+    FileChooserDialog,
+    FlowBox,
     IconSize,
     InputPurpose,
     Label,
+    ListBox,
+    ListBoxRow,
     Menu,
     MenuItem,
     MessageDialog,
     MessageType,
     Orientation,
+    Overlay,
     PolicyType,
+    Popover,
     PositionType,
     RadioButton,
+    ReliefStyle,
     ResponseType,
     Revealer,
     RevealerTransitionType,
     ScrolledWindow,
+    SearchEntry,
+    SelectionMode,
     Separator,
     SeparatorMenuItem,
     Stack,
     StackTransitionType,
     StyleContext,
     STYLE_PROVIDER_PRIORITY_APPLICATION,
+    TextView,
+    ToggleButton,
     Window,
     WindowType
 };
+use alias::AliasError;
+use chrono::Utc;
+use commands::CommandError;
 use connections::{Connections, Synac};
+use e2e::E2eError;
 use failure::Error;
 use functions::*;
-use gdk::Screen;
+use gdk::{ModifierType, Screen};
 use gtk::prelude::*;
+use highlight::Theme;
+use history::History;
+use i18n::{fill, tr, trn};
+use logger::Logger;
+use messages::{Dates, TimeFormat};
 use notify_rust::Notification;
-use pango::WrapMode;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use pango::{EllipsizeMode, WrapMode};
+use parser::ParseError;
+use permalink::Permalink;
 use rusqlite::Connection as SqlConnection;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
 use std::env;
-use std::fmt::Write;
-use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
 use std::net::SocketAddr;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::sync::atomic::Ordering as AtomicOrdering;
 use std::time::{Duration, Instant};
 use synac::common::{self, Packet};
 use xdg::BaseDirectories;
@@ -73,6 +118,34 @@ use xdg::BaseDirectories;
 #[fail(display = "sadly GTK+ doesn't support unicode paths")]
 struct UnicodePathError;
 
+struct Aliases {
+    container: GtkBox,
+
+    list: ListBox,
+    name: Entry,
+    expansion: Entry,
+    error: Label
+}
+struct BotConsole {
+    revealer: Revealer,
+    bots: ComboBoxText,
+    log: ListBox,
+    log_scroll: ScrolledWindow,
+    input: Entry,
+    args: Label,
+    history: RefCell<HashMap<String, History>>,
+    /// Bots we've sent commands to, whose messages count as replies
+    invoked: RefCell<HashSet<(SocketAddr, usize)>>,
+    /// Each server's lines and their authors, so switching servers switches logs
+    lines: RefCell<HashMap<SocketAddr, Vec<(String, Option<String>)>>>,
+    /// The server whose lines are in `log`
+    shown: Cell<Option<SocketAddr>>
+}
+struct Completion {
+    popover: Popover,
+    list: GtkBox,
+    cycle: RefCell<Option<completion::Cycle>>
+}
 struct EditChannel {
     container: GtkBox,
     edit: RefCell<Option<usize>>,
@@ -96,28 +169,126 @@ struct EditUser {
     radio_some: RadioButton,
     mode: GtkBox
 }
+struct EmojiPicker {
+    popover: Popover,
+    search: SearchEntry,
+    recent_label: Label,
+    recent: FlowBox,
+    all: FlowBox
+}
+struct HistorySearch {
+    revealer: Revealer,
+    entry: SearchEntry,
+    found: Cell<Option<usize>>,
+    stash: RefCell<String>
+}
+/// What we know of someone's key for private messages.
+struct PublicKey {
+    der: Vec<u8>,
+    verified: bool,
+    /// A different key they sent later, which isn't used until it's verified
+    pending: Option<Vec<u8>>
+}
+/// Newer history being loaded after following a link to an old message,
+/// page by page until it meets the latest messages.
+struct GapFill {
+    addr: SocketAddr,
+    channel: usize,
+    /// How many other message lists come back before the one we're waiting for
+    skip: usize,
+    /// Messages in the list being received, and the newest of them
+    count: usize,
+    newest: Option<usize>
+}
+/// The separator above a message that starts a new day.
+struct DayRow {
+    timestamp: i64,
+    label: Label,
+    row: ListBoxRow
+}
+struct MessageRow {
+    id: usize,
+    prev: Option<usize>,
+    edited: Option<i64>,
+    deleted: bool,
+    /// The author's name when the row was rendered, to notice renames
+    author: String,
+    /// A hash of the text, since edits in the same second keep the timestamp
+    text: u64,
+
+    day: Option<DayRow>,
+    row: ListBoxRow
+}
+struct Settings {
+    container: GtkBox,
+
+    deleted_keep: CheckButton,
+    deleted_reveal: CheckButton,
+    log_enabled: CheckButton,
+    log_format: Entry,
+    time_clock24: CheckButton,
+    time_custom: Entry,
+    time_dates: ComboBoxText
+}
 struct App {
+    basedirs: BaseDirectories,
     connections: Arc<Connections>,
     db: Rc<SqlConnection>,
 
+    bot_console: BotConsole,
     channel_add: Revealer,
     channel_name: Label,
+    channel_topic: Label,
     channels: GtkBox,
+    command_output: Label,
+    command_output_revealer: Revealer,
+    completion: Completion,
+    composer_silent: Cell<bool>,
+    /// The server and user whose private conversation is shown instead of a channel
+    direct: RefCell<Option<(SocketAddr, usize)>>,
+    direct_messages: GtkBox,
+    /// The server and channel the composer's text was typed for
+    draft_channel: RefCell<Option<(SocketAddr, usize)>>,
+    emoji_picker: EmojiPicker,
+    emoji_recent: RefCell<Vec<String>>,
+    gap_fill: RefCell<Option<GapFill>>,
+    history: RefCell<History>,
+    history_search: HistorySearch,
+    jump_latest: Revealer,
+    loading_older: Cell<bool>,
+    message_composer: TextView,
     message_edit: Revealer,
     message_edit_id: RefCell<Option<usize>>,
     message_edit_input: Entry,
     message_input: Revealer,
-    messages: GtkBox,
+    message_rows: RefCell<Vec<MessageRow>>,
+    messages: ListBox,
     messages_noread: Revealer,
     messages_scroll: ScrolledWindow,
+    private_key: PKey,
+    reveal_deleted: Cell<bool>,
+    /// The first visible message and how far down the viewport it was,
+    /// to keep it in place while older messages are prepended
+    scroll_anchor: Cell<Option<(usize, f64)>>,
+    scroll_target: RefCell<Option<usize>>,
     server_name: Label,
     servers: GtkBox,
     stack: Stack,
+    stack_aliases: Aliases,
     stack_edit_channel: EditChannel,
     stack_edit_server: EditServer,
     stack_edit_user: EditUser,
     stack_main: GtkBox,
+    stack_settings: Settings,
+    stick_to_bottom: Cell<bool>,
+    theme: Theme,
+    time_format: RefCell<TimeFormat>,
     typing: Label,
+    /// Indicators on the channel buttons, by channel id
+    typing_channels: RefCell<HashMap<usize, Revealer>>,
+    /// Indicators next to names in the user list, by user id
+    typing_users: RefCell<HashMap<usize, Revealer>>,
+    user_name: Label,
     user_stack: Stack,
     user_stack_edit: Entry,
     user_stack_text: EventBox,
@@ -163,6 +334,68 @@ fn main() {
                     server  TEXT    NOT NULL
                 )", &[])
         .expect("Couldn't create SQLite table");
+    db.execute("CREATE TABLE IF NOT EXISTS drafts (
+                    server  TEXT    NOT NULL,
+                    channel INTEGER NOT NULL,
+                    text    TEXT    NOT NULL,
+                    PRIMARY KEY (server, channel)
+                )", &[])
+        .expect("Couldn't create SQLite table");
+    db.execute("CREATE TABLE IF NOT EXISTS topics (
+                    server  TEXT    NOT NULL,
+                    channel INTEGER NOT NULL,
+                    text    TEXT    NOT NULL,
+                    PRIMARY KEY (server, channel)
+                )", &[])
+        .expect("Couldn't create SQLite table");
+    db.execute("CREATE TABLE IF NOT EXISTS history (
+                    server  TEXT    NOT NULL,
+                    channel INTEGER NOT NULL,
+                    text    TEXT    NOT NULL
+                )", &[])
+        .expect("Couldn't create SQLite table");
+    db.execute("CREATE TABLE IF NOT EXISTS public_keys (
+                    server   TEXT    NOT NULL,
+                    user     INTEGER NOT NULL,
+                    key      BLOB    NOT NULL,
+                    verified INTEGER NOT NULL DEFAULT 0,
+                    pending  BLOB,
+
+                    PRIMARY KEY (server, user)
+                )", &[])
+        .expect("Couldn't create SQLite table");
+    // Databases from before keys were pinned lack the pending column
+    if db.prepare("SELECT pending FROM public_keys LIMIT 0").is_err() {
+        db.execute("ALTER TABLE public_keys ADD COLUMN pending BLOB", &[])
+            .expect("Couldn't migrate SQLite table");
+    }
+    db.execute("CREATE TABLE IF NOT EXISTS private_messages (
+                    server    TEXT    NOT NULL,
+                    user      INTEGER NOT NULL,
+                    author    INTEGER NOT NULL,
+                    text      TEXT    NOT NULL,
+                    timestamp INTEGER NOT NULL
+                )", &[])
+        .expect("Couldn't create SQLite table");
+    db.execute("CREATE TABLE IF NOT EXISTS seen_nonces (
+                    server TEXT    NOT NULL,
+                    user   INTEGER NOT NULL,
+                    nonce  BLOB    NOT NULL,
+
+                    PRIMARY KEY (server, user, nonce)
+                )", &[])
+        .expect("Couldn't create SQLite table");
+    db.execute("CREATE TABLE IF NOT EXISTS queued_private (
+                    server TEXT    NOT NULL,
+                    user   INTEGER NOT NULL,
+                    data   BLOB    NOT NULL
+                )", &[])
+        .expect("Couldn't create SQLite table");
+    db.execute("CREATE TABLE IF NOT EXISTS aliases (
+                    name      TEXT NOT NULL PRIMARY KEY UNIQUE,
+                    expansion TEXT NOT NULL
+                )", &[])
+        .expect("Couldn't create SQLite table");
 
     let nick = {
         let mut stmt = db.prepare("SELECT value FROM data WHERE key = 'nick'").unwrap();
@@ -180,34 +413,104 @@ fn main() {
         }
     };
 
+    let logger = load_logger(&db, &basedirs);
+
     if let Err(err) = gtk::init() {
         eprintln!("gtk error: {}", err);
         return;
     }
 
     let window = Window::new(WindowType::Toplevel);
-    window.set_title("Synac GTK+ client");
+    window.set_title(&tr("Synac GTK+ client"));
     window.set_default_size(1000, 700);
 
-    let radio_none = RadioButton::new_with_label("Inherit channel's mode");
-    let radio_some = RadioButton::new_with_label_from_widget(&radio_none, "Use custom mode:");
+    let radio_none = RadioButton::new_with_label(&tr("Inherit channel's mode"));
+    let radio_some = RadioButton::new_with_label_from_widget(&radio_none, &tr("Use custom mode:"));
+
+    let dark = if let Some(settings) = window.get_settings() {
+        settings.get_property_gtk_application_prefer_dark_theme()
+    } else { false };
+    let theme = if dark { Theme::Dark } else { Theme::Light };
+
+    let time_format = load_time_format(&db);
+    let emoji_recent = load_emoji_recent(&db);
+    let private_key = load_private_key(&db);
+    let connections = Connections::new(&db, nick, logger);
+    let user_name = Label::new(&**connections.nick.read().unwrap());
 
     let app = Rc::new(App {
+        basedirs: basedirs,
+        bot_console: BotConsole {
+            revealer: Revealer::new(),
+            bots: ComboBoxText::new(),
+            log: ListBox::new(),
+            log_scroll: ScrolledWindow::new(None, None),
+            input: Entry::new(),
+            args: Label::new(""),
+            history: RefCell::new(HashMap::new()),
+            invoked: RefCell::new(HashSet::new()),
+            lines: RefCell::new(HashMap::new()),
+            shown: Cell::new(None)
+        },
         channel_add: Revealer::new(),
         channel_name: Label::new(""),
+        channel_topic: Label::new(""),
         channels: GtkBox::new(Orientation::Vertical, 2),
-        connections: Connections::new(&db, nick),
+        command_output: Label::new(""),
+        command_output_revealer: Revealer::new(),
+        completion: Completion {
+            popover: Popover::new(None::<&Button>),
+            list: GtkBox::new(Orientation::Horizontal, 2),
+            cycle: RefCell::new(None)
+        },
+        composer_silent: Cell::new(false),
+        direct: RefCell::new(None),
+        direct_messages: GtkBox::new(Orientation::Vertical, 2),
+        draft_channel: RefCell::new(None),
+        connections: connections,
         db: Rc::new(db),
+        emoji_picker: EmojiPicker {
+            popover: Popover::new(None::<&Button>),
+            search: SearchEntry::new(),
+            recent_label: Label::new(&*tr("Recently used")),
+            recent: FlowBox::new(),
+            all: FlowBox::new()
+        },
+        emoji_recent: RefCell::new(emoji_recent),
+        gap_fill: RefCell::new(None),
+        history: RefCell::new(History::default()),
+        history_search: HistorySearch {
+            revealer: Revealer::new(),
+            entry: SearchEntry::new(),
+            found: Cell::new(None),
+            stash: RefCell::new(String::new())
+        },
+        jump_latest: Revealer::new(),
+        loading_older: Cell::new(false),
+        message_composer: TextView::new(),
         message_edit: Revealer::new(),
         message_edit_id: RefCell::new(None),
         message_edit_input: Entry::new(),
         message_input: Revealer::new(),
-        messages: GtkBox::new(Orientation::Vertical, 3),
+        message_rows: RefCell::new(Vec::new()),
+        messages: ListBox::new(),
         messages_noread: Revealer::new(),
         messages_scroll: ScrolledWindow::new(None, None),
+        private_key: private_key,
+        reveal_deleted: Cell::new(false),
+        scroll_anchor: Cell::new(None),
+        scroll_target: RefCell::new(None),
         server_name: Label::new(""),
         servers: GtkBox::new(Orientation::Vertical, 2),
         stack: Stack::new(),
+        stack_aliases: Aliases {
+            container: GtkBox::new(Orientation::Vertical, 2),
+
+            list: ListBox::new(),
+            name: Entry::new(),
+            expansion: Entry::new(),
+            error: Label::new(None)
+        },
         stack_edit_channel: EditChannel {
             container: GtkBox::new(Orientation::Vertical, 2),
             edit: RefCell::new(None),
@@ -232,14 +535,32 @@ fn main() {
             mode: GtkBox::new(Orientation::Vertical, 2)
         },
         stack_main: GtkBox::new(Orientation::Horizontal, 10),
+        stack_settings: Settings {
+            container: GtkBox::new(Orientation::Vertical, 2),
+
+            deleted_keep: CheckButton::new_with_label(&tr("Keep a placeholder for deleted messages")),
+            deleted_reveal: CheckButton::new_with_label(&tr("Show the text of deleted messages in channels I moderate")),
+            log_enabled: CheckButton::new_with_label(&tr("Log channels to disk")),
+            log_format: Entry::new(),
+            time_clock24: CheckButton::new_with_label(&tr("Use 24-hour clock")),
+            time_custom: Entry::new(),
+            time_dates: ComboBoxText::new()
+        },
+        stick_to_bottom: Cell::new(true),
+        theme: theme,
+        time_format: RefCell::new(time_format),
+        user_name: user_name,
         user_stack: Stack::new(),
         user_stack_edit: Entry::new(),
         user_stack_text: EventBox::new(),
         users: GtkBox::new(Orientation::Vertical, 2),
         users_revealer: Revealer::new(),
         typing: Label::new(""),
+        typing_channels: RefCell::new(HashMap::new()),
+        typing_users: RefCell::new(HashMap::new()),
         window: window
     });
+    load_deleted_prefs(&app);
 
     app.channel_add.set_transition_type(RevealerTransitionType::SlideUp);
     app.message_edit.set_transition_type(RevealerTransitionType::SlideUp);
@@ -253,44 +574,23 @@ fn main() {
     app.stack.add(&app.stack_edit_server.container);
     app.stack.add(&app.stack_edit_channel.container);
     app.stack.add(&app.stack_edit_user.container);
+    app.stack.add(&app.stack_settings.container);
+    app.stack.add(&app.stack_aliases.container);
 
     app.user_stack.add(&app.user_stack_text);
     app.user_stack.add(&app.user_stack_edit);
 
-    let user_name = Label::new(&**app.connections.nick.read().unwrap());
-    add_class(&user_name, "bold");
+    add_class(&app.user_name, "bold");
 
     app.user_stack_edit.set_alignment(0.5);
 
     let app_clone = Rc::clone(&app);
-    let user_name_clone = user_name.clone();
     app.user_stack_edit.connect_activate(move |input| {
         let text = input.get_text().unwrap_or_default();
-        let old = app_clone.connections.nick.read().unwrap();
         app_clone.user_stack.set_visible_child(&app_clone.user_stack_text);
-        if text.is_empty() || text == *old {
-            return;
+        if let Err(err) = set_nick(&app_clone, &text) {
+            alert(&app_clone.window, MessageType::Warning, &err);
         }
-        user_name_clone.set_text(&text);
-
-        drop(old);
-
-        app_clone.connections.foreach(|synac| {
-            let result = synac.session.send(&Packet::LoginUpdate(common::LoginUpdate {
-                name: Some(text.clone()),
-                password_current: None,
-                password_new: None,
-                reset_token: false
-            }));
-            if let Err(err) = result {
-                let string = format!("failed to update server {}: {}", synac.addr, err.to_string());
-                alert(&app_clone.window, MessageType::Warning, &string);
-            }
-        });
-
-        app_clone.db.execute("REPLACE INTO data (key, value) VALUES ('nick', ?)", &[&text]).unwrap();
-
-        *app_clone.connections.nick.write().unwrap() = text;
     });
     let app_clone = Rc::clone(&app);
     app.user_stack_edit.connect_focus_out_event(move |_, _| {
@@ -300,8 +600,8 @@ fn main() {
 
     let servers_wrapper = GtkBox::new(Orientation::Vertical, 0);
 
-    user_name.set_property_margin(10);
-    app.user_stack_text.add(&user_name);
+    app.user_name.set_property_margin(10);
+    app.user_stack_text.add(&app.user_name);
 
     let app_clone = Rc::clone(&app);
     app.user_stack_text.connect_button_press_event(move |_, event| {
@@ -319,7 +619,7 @@ fn main() {
     render_servers(&app);
     servers_wrapper.add(&app.servers);
 
-    let add = Button::new_with_mnemonic("Add _Server");
+    let add = Button::new_with_mnemonic(&tr("Add _Server"));
     add_class(&add, "add");
     add.set_valign(Align::End);
     add.set_vexpand(true);
@@ -336,6 +636,47 @@ fn main() {
 
     servers_wrapper.add(&add);
 
+    let settings = Button::new_with_mnemonic(&tr("S_ettings"));
+    add_class(&settings, "add");
+
+    let app_clone = Rc::clone(&app);
+    settings.connect_clicked(move |_| {
+        let log_enabled = get_data(&app_clone.db, "log_enabled").map(|value| value == "1").unwrap_or(false);
+        let log_format  = get_data(&app_clone.db, "log_format").unwrap_or_else(|| String::from(logger::DEFAULT_FORMAT));
+
+        app_clone.stack_settings.log_enabled.set_active(log_enabled);
+        app_clone.stack_settings.log_format.set_text(&log_format);
+
+        let time_format = app_clone.time_format.borrow();
+        app_clone.stack_settings.time_clock24.set_active(time_format.clock24);
+        app_clone.stack_settings.time_dates.set_active_id(Some(time_format.dates.id()));
+        app_clone.stack_settings.time_custom.set_text(&time_format.custom);
+
+        let keep = app_clone.connections.keep_deleted.load(AtomicOrdering::Relaxed);
+        app_clone.stack_settings.deleted_keep.set_active(keep);
+        app_clone.stack_settings.deleted_reveal.set_active(app_clone.reveal_deleted.get());
+        app_clone.stack_settings.deleted_reveal.set_sensitive(keep);
+
+        app_clone.stack.set_visible_child(&app_clone.stack_settings.container);
+    });
+
+    servers_wrapper.add(&settings);
+
+    let aliases = Button::new_with_mnemonic(&tr("_Aliases"));
+    add_class(&aliases, "add");
+
+    let app_clone = Rc::clone(&app);
+    aliases.connect_clicked(move |_| {
+        app_clone.stack_aliases.name.set_text("");
+        app_clone.stack_aliases.expansion.set_text("");
+        app_clone.stack_aliases.error.set_text("");
+        render_aliases(&app_clone);
+
+        app_clone.stack.set_visible_child(&app_clone.stack_aliases.container);
+    });
+
+    servers_wrapper.add(&aliases);
+
     app.stack_main.add(&servers_wrapper);
 
     app.stack_main.add(&Separator::new(Orientation::Horizontal));
@@ -349,8 +690,9 @@ fn main() {
     channels_wrapper.add(&Separator::new(Orientation::Vertical));
 
     channels_wrapper.add(&app.channels);
+    channels_wrapper.add(&app.direct_messages);
 
-    let add = Button::new_with_mnemonic("Add _Channel");
+    let add = Button::new_with_mnemonic(&tr("Add _Channel"));
     add_class(&add, "add");
     add.set_valign(Align::End);
     add.set_vexpand(true);
@@ -379,6 +721,10 @@ fn main() {
 
     header.add(&app.channel_name);
 
+    add_class(&app.channel_topic, "topic");
+    app.channel_topic.set_ellipsize(EllipsizeMode::End);
+    header.add(&app.channel_topic);
+
     let toggle_users = Button::new_from_icon_name("user-available", IconSize::Menu.into());
     add_class(&toggle_users, "icon");
     toggle_users.set_hexpand(true);
@@ -391,15 +737,33 @@ fn main() {
 
     header.add(&toggle_users);
 
+    let toggle_bots = Button::new_from_icon_name("utilities-terminal", IconSize::Menu.into());
+    add_class(&toggle_bots, "icon");
+    toggle_bots.set_tooltip_text(&*tr("Bot console"));
+
+    let app_clone = Rc::clone(&app);
+    toggle_bots.connect_clicked(move |_| {
+        let console = &app_clone.bot_console;
+        let reveal = !console.revealer.get_reveal_child();
+        console.revealer.set_reveal_child(reveal);
+        if reveal {
+            console.input.grab_focus();
+        }
+    });
+
+    header.add(&toggle_bots);
+
     content.add(&header);
     content.add(&Separator::new(Orientation::Vertical));
 
-    let noread = Label::new("You do not have the read permission in this channel");
+    let noread = Label::new(&*tr("You do not have the read permission in this channel"));
     add_class(&noread, "warning");
     app.messages_noread.add(&noread);
 
     content.add(&app.messages_noread);
 
+    add_class(&app.messages, "messages");
+    app.messages.set_selection_mode(SelectionMode::None);
     app.messages.set_valign(Align::End);
     app.messages.set_vexpand(true);
     app.messages_scroll.add(&app.messages);
@@ -407,15 +771,29 @@ fn main() {
     app.messages_scroll.set_policy(PolicyType::Never, PolicyType::Always);
     app.messages_scroll.set_overlay_scrolling(false);
 
-    app.messages_scroll.get_vadjustment().unwrap().connect_changed(move |vadjustment| {
-        let upper = vadjustment.get_upper() - vadjustment.get_page_size();
-        if vadjustment.get_value() + 100.0 >= upper {
-            vadjustment.set_value(upper);
+    let vadjustment = app.messages_scroll.get_vadjustment().unwrap();
+    let app_clone = Rc::clone(&app);
+    vadjustment.connect_changed(move |vadjustment| {
+        // set_scroll_anchor puts the viewport back once older messages are in
+        if app_clone.scroll_anchor.get().is_some() {
+            return;
+        }
+        if app_clone.stick_to_bottom.get() {
+            vadjustment.set_value(vadjustment.get_upper() - vadjustment.get_page_size());
         }
     });
     let app_clone = Rc::clone(&app);
+    vadjustment.connect_value_changed(move |vadjustment| {
+        let value = vadjustment.get_value();
+        let upper = vadjustment.get_upper();
+
+        let bottom = value + vadjustment.get_page_size() >= upper - 5.0;
+        app_clone.stick_to_bottom.set(bottom);
+        app_clone.jump_latest.set_reveal_child(!bottom);
+    });
+    let app_clone = Rc::clone(&app);
     app.messages_scroll.connect_edge_reached(move |_, pos| {
-        if pos != PositionType::Top {
+        if pos != PositionType::Top || app_clone.loading_older.get() {
             return;
         }
         if let Some(addr) = *app_clone.connections.current_server.lock().unwrap() {
@@ -431,17 +809,33 @@ fn main() {
                             limit: common::LIMIT_BULK
                         })) {
                             eprintln!("error sending packet: {}", err);
+                        } else {
+                            app_clone.loading_older.set(true);
                         }
                     }
                 }
             });
         }
     });
-    content.add(&app.messages_scroll);
+    let jump_latest = Button::new_with_mnemonic(&tr("_Jump to latest"));
+    let app_clone = Rc::clone(&app);
+    jump_latest.connect_clicked(move |_| scroll_to_bottom(&app_clone));
+
+    app.jump_latest.set_transition_type(RevealerTransitionType::Crossfade);
+    app.jump_latest.set_halign(Align::End);
+    app.jump_latest.set_valign(Align::End);
+    app.jump_latest.set_margin_right(20);
+    app.jump_latest.set_margin_bottom(10);
+    app.jump_latest.add(&jump_latest);
+
+    let messages_overlay = Overlay::new();
+    messages_overlay.add(&app.messages_scroll);
+    messages_overlay.add_overlay(&app.jump_latest);
+    content.add(&messages_overlay);
 
     let message_edit = GtkBox::new(Orientation::Vertical, 2);
 
-    message_edit.add(&Label::new("Edit message"));
+    message_edit.add(&Label::new(&*tr("Edit message")));
 
     let app_clone = Rc::clone(&app);
     app.message_edit_input.connect_activate(move |input| {
@@ -459,7 +853,7 @@ fn main() {
 
                 if let Err(err) = synac.session.send(&Packet::MessageUpdate(common::MessageUpdate {
                     id: app_clone.message_edit_id.borrow().expect("wait how is this variable not set"),
-                    text: text.into_bytes()
+                    text: emoji::replace(&text).into_bytes()
                 })) {
                     eprintln!("failed to send packet: {}", err);
                 }
@@ -471,7 +865,7 @@ fn main() {
 
     message_edit.add(&app.message_edit_input);
 
-    let message_edit_cancel = Button::new_with_mnemonic("_Cancel");
+    let message_edit_cancel = Button::new_with_mnemonic(&tr("_Cancel"));
     let app_clone = Rc::clone(&app);
     message_edit_cancel.connect_clicked(move |_| {
         app_clone.message_edit.set_reveal_child(false);
@@ -481,17 +875,144 @@ fn main() {
     app.message_edit.add(&message_edit);
     content.add(&app.message_edit);
 
-    let input = Entry::new();
-    input.set_hexpand(true);
-    input.set_placeholder_text("Send a message...");
+    let composer_preview = Label::new(None);
+    composer_preview.set_line_wrap(true);
+    composer_preview.set_line_wrap_mode(WrapMode::WordChar);
+    composer_preview.set_xalign(0.0);
+    composer_preview.set_property_margin(5);
+    let app_clone = Rc::clone(&app);
+    composer_preview.connect_activate_link(move |_, uri| {
+        open_link(&app_clone, uri);
+        Inhibit(true)
+    });
+    let composer_preview_revealer = Revealer::new();
+    composer_preview_revealer.set_transition_type(RevealerTransitionType::SlideUp);
+    composer_preview_revealer.add(&composer_preview);
+    content.add(&composer_preview_revealer);
+
+    let input_box = GtkBox::new(Orientation::Horizontal, 2);
+
+    let input = &app.message_composer;
+    input.set_wrap_mode(gtk::WrapMode::WordChar);
+    input.set_accepts_tab(false);
+    input.set_left_margin(5);
+    input.set_right_margin(5);
+    input.set_tooltip_text(&*tr("Enter sends, Shift+Enter starts a new line.\nCtrl+Up, Ctrl+Down and Ctrl+R recall sent messages."));
+
+    // Grow with the text, up to a few lines
+    let input_scroll = ScrolledWindow::new(None, None);
+    input_scroll.set_policy(PolicyType::Never, PolicyType::Automatic);
+    input_scroll.set_propagate_natural_height(true);
+    input_scroll.set_max_content_height(150);
+    input_scroll.set_hexpand(true);
+    input_scroll.add(input);
+
+    let buffer = input.get_buffer().unwrap();
+
+    let emoji_complete = Popover::new(Some(input));
+    emoji_complete.set_modal(false);
+    emoji_complete.set_position(PositionType::Top);
+    let emoji_complete_list = GtkBox::new(Orientation::Horizontal, 2);
+    emoji_complete.add(&emoji_complete_list);
+
+    app.completion.popover.set_relative_to(Some(input));
+    app.completion.popover.set_modal(false);
+    app.completion.popover.set_position(PositionType::Top);
+    app.completion.popover.add(&app.completion.list);
+
+    let app_clone = Rc::clone(&app);
+    buffer.connect_changed(move |_| {
+        // Restoring a draft that ends in a shortcode shouldn't pop up suggestions
+        if app_clone.composer_silent.get() {
+            emoji_complete.hide();
+            return;
+        }
+        render_emoji_completion(&app_clone, &app_clone.message_composer, &emoji_complete, &emoji_complete_list);
+    });
+
+    // Saved a moment after typing, so a crash doesn't lose the draft
+    let draft_pending = Rc::new(Cell::new(false));
+    let app_clone = Rc::clone(&app);
+    buffer.connect_changed(move |_| {
+        if app_clone.composer_silent.get() || draft_pending.get() {
+            return;
+        }
+        draft_pending.set(true);
+
+        let app_clone = Rc::clone(&app_clone);
+        let draft_pending = Rc::clone(&draft_pending);
+        gtk::timeout_add_seconds(2, move || {
+            draft_pending.set(false);
+            save_draft(&app_clone);
+            Continue(false)
+        });
+    });
+
+    let preview = ToggleButton::new_with_mnemonic(&tr("_Preview"));
+    preview.set_relief(ReliefStyle::None);
+    preview.set_tooltip_text(&*tr("Show how the message will look"));
+
+    let app_clone = Rc::clone(&app);
+    let composer_preview_clone = composer_preview.clone();
+    let preview_clone = preview.clone();
+    buffer.connect_changed(move |_| {
+        if preview_clone.get_active() {
+            render_preview(&app_clone, &composer_preview_clone);
+        }
+    });
+    let app_clone = Rc::clone(&app);
+    preview.connect_toggled(move |preview| {
+        if preview.get_active() {
+            render_preview(&app_clone, &composer_preview);
+        }
+        composer_preview_revealer.set_reveal_child(preview.get_active());
+        app_clone.message_composer.grab_focus();
+    });
 
     let typing_duration = Duration::from_secs(common::TYPING_TIMEOUT as u64 / 2); // TODO: const fn
     let typing_last = RefCell::new(Instant::now());
 
     let app_clone = Rc::clone(&app);
-    input.connect_key_press_event(move |_, event| {
-        if event.get_keyval() != 65362 {
-            // hardcoded value because gdk::enums::key::uparrow doesn't work
+    input.connect_key_press_event(move |input, event| {
+        let keyval = event.get_keyval();
+        if keyval != 65289 {
+            reset_completion(&app_clone);
+        }
+        if event.get_state().contains(ModifierType::CONTROL_MASK) {
+            // ctrl+up/down walk through what was sent here, ctrl+r searches it
+            match keyval {
+                65362 => recall_history(&app_clone, true),
+                65364 => recall_history(&app_clone, false),
+                114 | 82 => start_history_search(&app_clone),
+                // ctrl+enter sends like enter does
+                65293 | 65421 => send_message(&app_clone),
+                // tab completion and editing with up are for plain keys only
+                _ => return Inhibit(false)
+            }
+            return Inhibit(true);
+        }
+        if keyval == 65293 || keyval == 65421 {
+            // enter sends, shift+enter is left to insert a newline
+            if event.get_state().contains(ModifierType::SHIFT_MASK) {
+                return Inhibit(false);
+            }
+            send_message(&app_clone);
+            return Inhibit(true);
+        }
+        if keyval == 65289 {
+            // tab completes the emoji shortcode being typed
+            if let Some((start, end, query)) = emoji_query(input) {
+                if let Some(&(_, emoji)) = emoji::search(&query).first() {
+                    complete_emoji(&app_clone, input, start, end, emoji);
+                    return Inhibit(true);
+                }
+            }
+            // otherwise, nicks, channels, bots and commands
+            return Inhibit(complete_word(&app_clone));
+        }
+        if keyval != 65362 || !composer_text(input).is_empty() {
+            // hardcoded value because gdk::enums::key::uparrow doesn't work.
+            // with text in the composer, up arrow moves between lines instead.
             return Inhibit(false);
         }
         if let Some(addr) = *app_clone.connections.current_server.lock().unwrap() {
@@ -502,7 +1023,8 @@ fn main() {
                 if synac.current_channel.is_none() { return; }
                 let channel = synac.current_channel.unwrap();
 
-                if let Some(msg) = synac.messages.get(channel).iter().rev().find(|msg| msg.author == synac.user) {
+                if let Some(msg) = synac.messages.get(channel).iter().rev()
+                        .find(|msg| msg.author == synac.user && !synac.messages.is_deleted(msg.id)) {
                     *app_clone.message_edit_id.borrow_mut() = Some(msg.id);
                     app_clone.message_edit_input.set_text(&*String::from_utf8_lossy(&msg.text));
                     app_clone.message_edit.set_reveal_child(true);
@@ -520,7 +1042,11 @@ fn main() {
         Inhibit(false)
     });
     let app_clone = Rc::clone(&app);
-    input.connect_property_text_notify(move |_| {
+    buffer.connect_changed(move |_| {
+        if app_clone.composer_silent.get() {
+            return;
+        }
+        hide_command_output(&app_clone);
         let mut typing_last = typing_last.borrow_mut();
         if typing_last.elapsed() < typing_duration {
             return;
@@ -541,86 +1067,94 @@ fn main() {
             });
         }
     });
-    let app_clone = Rc::clone(&app);
-    input.connect_activate(move |input| {
-        let text = input.get_text().unwrap_or_default();
-        if text.is_empty() {
-            return;
-        }
-        input.set_sensitive(false);
-        if let Some(addr) = *app_clone.connections.current_server.lock().unwrap() {
-            app_clone.connections.execute(addr, |result| {
-                if result.is_err() {
-                    return;
-                }
-                let synac = result.unwrap();
-                if text.starts_with('!') {
-                    let mut args = parser::parse(&text[1..]);
-                    if args.len() < 2 {
-                        alert(&app_clone.window, MessageType::Info, "!<user> <command> [args...]");
-                        return;
-                    }
 
-                    let recipient = args.remove(0);
+    input_box.add(&input_scroll);
 
-                    let mut user_id = None;
-                    for user in synac.state.users.values() {
-                        if user.bot && user.name == recipient {
-                            user_id = Some(user.id);
-                            break;
-                        }
-                    }
+    let emoji_button = Button::new_with_label("😀");
+    emoji_button.set_tooltip_text(&*tr("Insert emoji"));
+    emoji_button.set_relief(ReliefStyle::None);
 
-                    if user_id.is_none() {
-                        alert(&app_clone.window, MessageType::Warning, "No bot with that id.");
-                        return;
-                    }
+    let picker = &app.emoji_picker;
+    picker.popover.set_relative_to(Some(&emoji_button));
+    picker.popover.set_position(PositionType::Top);
 
-                    let result = synac.session.send(&Packet::Command(common::Command {
-                        args: args,
-                        recipient: user_id.unwrap()
-                    }));
-                    if let Err(err) = result {
-                        eprintln!("failed to send packet: {}", err);
-                        return;
-                    }
-                    return;
-                }
-                if synac.current_channel.is_none() {
-                    return;
-                }
-                let channel = synac.current_channel.unwrap();
-                let result = synac.session.send(&Packet::MessageCreate(common::MessageCreate {
-                    channel: channel,
-                    text: text.into_bytes()
-                }));
-                if let Err(err) = result {
-                    if let Ok(io_err) = err.downcast::<IoError>() {
-                        if io_err.kind() != IoErrorKind::BrokenPipe {
-                            return;
-                        }
-                    }
+    let picker_box = GtkBox::new(Orientation::Vertical, 2);
+    picker_box.set_property_margin(5);
+    picker_box.add(&picker.search);
 
-                    let mut stmt = app_clone.db.prepare_cached("SELECT hash, token FROM servers WHERE ip = ?").unwrap();
-                    let mut rows = stmt.query(&[&addr.to_string()]).unwrap();
+    picker.recent_label.set_xalign(0.0);
+    add_class(&picker.recent_label, "emoji-heading");
+    for flowbox in &[&picker.recent, &picker.all] {
+        flowbox.set_max_children_per_line(8);
+        flowbox.set_min_children_per_line(8);
+        flowbox.set_selection_mode(SelectionMode::None);
+        flowbox.set_homogeneous(true);
+    }
+    let picker_list = GtkBox::new(Orientation::Vertical, 2);
+    picker_list.add(&picker.recent_label);
+    picker_list.add(&picker.recent);
+    picker_list.add(&picker.all);
+
+    let picker_scroll = ScrolledWindow::new(None, None);
+    picker_scroll.set_policy(PolicyType::Never, PolicyType::Automatic);
+    picker_scroll.set_size_request(-1, 250);
+    picker_scroll.add(&picker_list);
+    picker_box.add(&picker_scroll);
+    picker.popover.add(&picker_box);
 
-                    if let Some(row) = rows.next() {
-                        let row = row.unwrap();
+    let app_clone = Rc::clone(&app);
+    picker.search.connect_search_changed(move |_| {
+        render_emoji_picker(&app_clone, &app_clone.message_composer);
+    });
+    let app_clone = Rc::clone(&app);
+    emoji_button.connect_clicked(move |_| {
+        let picker = &app_clone.emoji_picker;
+        picker.search.set_text("");
+        picker.popover.show_all();
+        render_emoji_picker(&app_clone, &app_clone.message_composer);
+        picker.search.grab_focus();
+    });
+    input_box.add(&emoji_button);
+    input_box.add(&preview);
 
-                        let hash = row.get(0);
-                        let token = row.get(1);
+    let search = &app.history_search;
+    let search_box = GtkBox::new(Orientation::Horizontal, 5);
+    search_box.add(&Label::new(&*tr("Search sent messages:")));
+    search.entry.set_hexpand(true);
+    search_box.add(&search.entry);
+    search.revealer.set_transition_type(RevealerTransitionType::SlideUp);
+    search.revealer.add(&search_box);
 
-                        connect(&app_clone, addr, hash, token);
-                    }
-                }
-            });
+    let app_clone = Rc::clone(&app);
+    search.entry.connect_search_changed(move |_| search_history(&app_clone, false));
+    let app_clone = Rc::clone(&app);
+    search.entry.connect_key_press_event(move |_, event| {
+        let keyval = event.get_keyval();
+        if event.get_state().contains(ModifierType::CONTROL_MASK) && (keyval == 114 || keyval == 82) {
+            // ctrl+r again looks further back
+            search_history(&app_clone, true);
+            return Inhibit(true);
         }
-        input.set_text("");
-        input.set_sensitive(true);
-        input.grab_focus();
+        Inhibit(false)
     });
-
-    app.message_input.add(&input);
+    let app_clone = Rc::clone(&app);
+    search.entry.connect_activate(move |_| end_history_search(&app_clone, true));
+    let app_clone = Rc::clone(&app);
+    search.entry.connect_stop_search(move |_| end_history_search(&app_clone, false));
+
+    app.command_output.set_line_wrap(true);
+    app.command_output.set_line_wrap_mode(WrapMode::WordChar);
+    app.command_output.set_selectable(true);
+    app.command_output.set_xalign(0.0);
+    app.command_output.set_property_margin(5);
+    app.command_output_revealer.set_transition_type(RevealerTransitionType::SlideUp);
+    app.command_output_revealer.add(&app.command_output);
+
+    let composer_box = GtkBox::new(Orientation::Vertical, 2);
+    composer_box.add(&app.command_output_revealer);
+    composer_box.add(&search.revealer);
+    composer_box.add(&input_box);
+    app.message_input.add(&composer_box);
     content.add(&app.message_input);
 
     app.typing.set_xalign(0.0);
@@ -633,35 +1167,80 @@ fn main() {
     app.users_revealer.add(&app.users);
     app.stack_main.add(&app.users_revealer);
 
+    let console = &app.bot_console;
+    let console_box = GtkBox::new(Orientation::Vertical, 5);
+    console_box.set_property_margin(10);
+    console_box.set_size_request(350, -1);
+
+    let label = Label::new(&*tr("Bot console"));
+    add_class(&label, "bold");
+    console_box.add(&label);
+    console_box.add(&console.bots);
+
+    console.log.set_selection_mode(SelectionMode::None);
+    add_class(&console.log, "messages");
+    console.log_scroll.set_policy(PolicyType::Never, PolicyType::Automatic);
+    console.log_scroll.set_vexpand(true);
+    console.log_scroll.add(&console.log);
+    console_box.add(&console.log_scroll);
+
+    console.input.set_placeholder_text(&*tr("command [arguments...]"));
+    console_box.add(&console.input);
+
+    console.args.set_line_wrap(true);
+    console.args.set_xalign(0.0);
+    add_class(&console.args, "time");
+    console_box.add(&console.args);
+    render_bot_args(&app);
+
+    let app_clone = Rc::clone(&app);
+    console.input.connect_changed(move |_| render_bot_args(&app_clone));
+    let app_clone = Rc::clone(&app);
+    console.input.connect_activate(move |_| send_bot_console(&app_clone));
+    let app_clone = Rc::clone(&app);
+    console.input.connect_key_press_event(move |_, event| {
+        // hardcoded up and down arrows, like the composer
+        match event.get_keyval() {
+            65362 => recall_bot_history(&app_clone, true),
+            65364 => recall_bot_history(&app_clone, false),
+            _ => return Inhibit(false)
+        }
+        Inhibit(true)
+    });
+    let app_clone = Rc::clone(&app);
+    console.bots.connect_changed(move |_| render_bot_args(&app_clone));
+
+    console.revealer.set_transition_type(RevealerTransitionType::SlideLeft);
+    console.revealer.add(&console_box);
+    app.stack_main.add(&console.revealer);
+
     app.stack_edit_server.container.set_property_margin(10);
 
-    app.stack_edit_server.name.set_placeholder_text("Server name...");
+    app.stack_edit_server.name.set_placeholder_text(&*tr("Server name..."));
     app.stack_edit_server.container.add(&app.stack_edit_server.name);
-    app.stack_edit_server.container.add(&Label::new("The server name. This can be anything you want it to."));
+    app.stack_edit_server.container.add(&Label::new(&*tr("The server name. This can be anything you want it to.")));
 
-    app.stack_edit_server.server.set_placeholder_text("Server IP...");
+    app.stack_edit_server.server.set_placeholder_text(&*tr("Server IP..."));
     app.stack_edit_server.container.add(&app.stack_edit_server.server);
 
-    let mut string = String::with_capacity(43 + 4 + 1);
-    write!(string, "The server IP address. The default port is {}.", common::DEFAULT_PORT).unwrap();
-
+    let string = fill(&tr("The server IP address. The default port is {}."), &[&common::DEFAULT_PORT.to_string()]);
     app.stack_edit_server.container.add(&Label::new(&*string));
 
-    app.stack_edit_server.hash.set_placeholder_text("Server's certificate hash...");
+    app.stack_edit_server.hash.set_placeholder_text(&*tr("Server's certificate hash..."));
     app.stack_edit_server.container.add(&app.stack_edit_server.hash);
-    app.stack_edit_server.container.add(&Label::new("The server's certificate public key hash.\n\
-                               This is to verify nobody is snooping on your connection"));
+    app.stack_edit_server.container.add(&Label::new(&*tr("The server's certificate public key hash.\n\
+                               This is to verify nobody is snooping on your connection")));
 
     let edit_server_controls = GtkBox::new(Orientation::Horizontal, 2);
 
-    let edit_server_cancel = Button::new_with_mnemonic("_Cancel");
+    let edit_server_cancel = Button::new_with_mnemonic(&tr("_Cancel"));
     let app_clone = Rc::clone(&app);
     edit_server_cancel.connect_clicked(move |_| {
         app_clone.stack.set_visible_child(&app_clone.stack_main);
     });
     edit_server_controls.add(&edit_server_cancel);
 
-    let edit_server_ok = Button::new_with_mnemonic("_Ok");
+    let edit_server_ok = Button::new_with_mnemonic(&tr("_Ok"));
 
     let app_clone = Rc::clone(&app);
     edit_server_ok.connect_clicked(move |_| {
@@ -688,18 +1267,18 @@ fn main() {
 
     app.stack_edit_channel.container.set_property_margin(10);
 
-    app.stack_edit_channel.name.set_placeholder_text("Channel name...");
+    app.stack_edit_channel.name.set_placeholder_text(&*tr("Channel name..."));
     app.stack_edit_channel.container.add(&app.stack_edit_channel.name);
 
-    app.stack_edit_channel.container.add(&Label::new("The channel name."));
+    app.stack_edit_channel.container.add(&Label::new(&*tr("The channel name.")));
 
-    let label = Label::new("Default permissions for bots: ");
+    let label = Label::new(&*tr("Default permissions for bots: "));
     label.set_xalign(0.0);
     app.stack_edit_channel.container.add(&label);
 
     app.stack_edit_channel.container.add(&app.stack_edit_channel.mode_bots);
 
-    let label = Label::new("Default permissions for users: ");
+    let label = Label::new(&*tr("Default permissions for users: "));
     label.set_xalign(0.0);
     app.stack_edit_channel.container.add(&label);
 
@@ -707,14 +1286,14 @@ fn main() {
 
     let edit_channel_controls = GtkBox::new(Orientation::Horizontal, 2);
 
-    let edit_channel_cancel = Button::new_with_mnemonic("_Cancel");
+    let edit_channel_cancel = Button::new_with_mnemonic(&tr("_Cancel"));
     let app_clone = Rc::clone(&app);
     edit_channel_cancel.connect_clicked(move |_| {
         app_clone.stack.set_visible_child(&app_clone.stack_main);
     });
     edit_channel_controls.add(&edit_channel_cancel);
 
-    let edit_channel_ok = Button::new_with_mnemonic("_Ok");
+    let edit_channel_ok = Button::new_with_mnemonic(&tr("_Ok"));
 
     let app_clone = Rc::clone(&app);
     edit_channel_ok.connect_clicked(move |_| {
@@ -775,7 +1354,7 @@ fn main() {
 
     let edit_user_controls = GtkBox::new(Orientation::Horizontal, 2);
 
-    let edit_user_cancel = Button::new_with_mnemonic("_Cancel");
+    let edit_user_cancel = Button::new_with_mnemonic(&tr("_Cancel"));
 
     let app_clone = Rc::clone(&app);
     edit_user_cancel.connect_clicked(move |_| {
@@ -784,7 +1363,7 @@ fn main() {
 
     edit_user_controls.add(&edit_user_cancel);
 
-    let edit_user_ok = Button::new_with_mnemonic("_Ok");
+    let edit_user_ok = Button::new_with_mnemonic(&tr("_Ok"));
 
     let app_clone = Rc::clone(&app);
     edit_user_ok.connect_clicked(move |_| {
@@ -819,6 +1398,174 @@ fn main() {
     edit_user_controls.add(&edit_user_ok);
     app.stack_edit_user.container.add(&edit_user_controls);
 
+    app.stack_aliases.container.set_property_margin(10);
+
+    app.stack_aliases.container.add(&Label::new(&*tr("Type /name or !name to send what an alias expands to.\n\
+                               $1 to $9 are replaced by arguments, $* by everything after the name and $$ by a $.")));
+
+    app.stack_aliases.list.set_selection_mode(SelectionMode::None);
+    let aliases_scroll = ScrolledWindow::new(None, None);
+    aliases_scroll.set_policy(PolicyType::Never, PolicyType::Automatic);
+    aliases_scroll.set_vexpand(true);
+    aliases_scroll.add(&app.stack_aliases.list);
+    app.stack_aliases.container.add(&aliases_scroll);
+
+    app.stack_aliases.name.set_placeholder_text(&*tr("Alias name..."));
+    app.stack_aliases.container.add(&app.stack_aliases.name);
+
+    app.stack_aliases.expansion.set_placeholder_text(&*tr("Expands to..."));
+    app.stack_aliases.container.add(&app.stack_aliases.expansion);
+    app.stack_aliases.container.add(&Label::new(&*tr("A message, a /command or a !bot command, such as: !ci deploy $1 --branch=$2")));
+    add_class(&app.stack_aliases.error, "error");
+    app.stack_aliases.container.add(&app.stack_aliases.error);
+
+    let app_clone = Rc::clone(&app);
+    app.stack_aliases.expansion.connect_activate(move |_| save_alias(&app_clone));
+
+    let alias_transfer = GtkBox::new(Orientation::Horizontal, 2);
+
+    let alias_save = Button::new_with_mnemonic(&tr("_Save alias"));
+    let app_clone = Rc::clone(&app);
+    alias_save.connect_clicked(move |_| save_alias(&app_clone));
+    alias_transfer.add(&alias_save);
+
+    let export = Button::new_with_mnemonic(&tr("E_xport aliases..."));
+    let app_clone = Rc::clone(&app);
+    export.connect_clicked(move |_| export_aliases(&app_clone));
+    alias_transfer.add(&export);
+
+    let import = Button::new_with_mnemonic(&tr("_Import aliases..."));
+    let app_clone = Rc::clone(&app);
+    import.connect_clicked(move |_| import_aliases(&app_clone));
+    alias_transfer.add(&import);
+
+    app.stack_aliases.container.add(&alias_transfer);
+
+    let aliases_back = Button::new_with_mnemonic(&tr("_Back"));
+    let app_clone = Rc::clone(&app);
+    aliases_back.connect_clicked(move |_| {
+        app_clone.stack.set_visible_child(&app_clone.stack_main);
+    });
+    app.stack_aliases.container.add(&aliases_back);
+
+    app.stack_settings.container.set_property_margin(10);
+
+    app.stack_settings.container.add(&app.stack_settings.log_enabled);
+    app.stack_settings.container.add(&Label::new(&*tr("Append every received message to a file, one per channel and day.")));
+
+    app.stack_settings.log_format.set_placeholder_text(&*tr("Log line format..."));
+    app.stack_settings.container.add(&app.stack_settings.log_format);
+
+    let logs = app.basedirs.get_data_home().join("logs");
+    let string = fill(&tr("The format of each line. Supports strftime, {author}, {channel} and {text}.\n\
+                           Logs are saved in {}"), &[&logs.display().to_string()]);
+
+    app.stack_settings.container.add(&Label::new(&*string));
+
+    let label = Label::new(&*tr("Timestamps: "));
+    label.set_xalign(0.0);
+    app.stack_settings.container.add(&label);
+
+    app.stack_settings.container.add(&app.stack_settings.time_clock24);
+
+    app.stack_settings.time_dates.append(Some(Dates::Relative.id()), &tr("Relative dates (Today, Yesterday)"));
+    app.stack_settings.time_dates.append(Some(Dates::Absolute.id()), &tr("Absolute dates"));
+    app.stack_settings.time_dates.append(Some(Dates::Iso.id()), &tr("ISO-8601"));
+    app.stack_settings.time_dates.append(Some(Dates::Custom.id()), &tr("Custom pattern"));
+    app.stack_settings.container.add(&app.stack_settings.time_dates);
+
+    let app_clone = Rc::clone(&app);
+    app.stack_settings.time_dates.connect_changed(move |combo| {
+        let custom = combo.get_active_id().and_then(|id| Dates::from_id(&id)) == Some(Dates::Custom);
+        app_clone.stack_settings.time_custom.set_sensitive(custom);
+        app_clone.stack_settings.time_clock24.set_sensitive(!custom);
+    });
+
+    app.stack_settings.time_custom.set_placeholder_text(&*tr("Custom timestamp pattern..."));
+    app.stack_settings.container.add(&app.stack_settings.time_custom);
+    app.stack_settings.container.add(&Label::new(&*tr("A strftime pattern used for message timestamps, such as %d/%m %H:%M")));
+
+    app.stack_settings.container.add(&app.stack_settings.deleted_keep);
+    app.stack_settings.container.add(&app.stack_settings.deleted_reveal);
+
+    let app_clone = Rc::clone(&app);
+    app.stack_settings.deleted_keep.connect_toggled(move |check| {
+        app_clone.stack_settings.deleted_reveal.set_sensitive(check.get_active());
+    });
+
+    let transfer = GtkBox::new(Orientation::Horizontal, 2);
+
+    let export = Button::new_with_mnemonic(&tr("E_xport settings..."));
+    let app_clone = Rc::clone(&app);
+    export.connect_clicked(move |_| export_settings(&app_clone));
+    transfer.add(&export);
+
+    let import = Button::new_with_mnemonic(&tr("_Import settings..."));
+    let app_clone = Rc::clone(&app);
+    import.connect_clicked(move |_| import_settings(&app_clone));
+    transfer.add(&import);
+
+    app.stack_settings.container.add(&transfer);
+    app.stack_settings.container.add(&Label::new(&*tr("Move servers, muted channels, aliases and preferences between machines.")));
+
+    let settings_controls = GtkBox::new(Orientation::Horizontal, 2);
+
+    let settings_cancel = Button::new_with_mnemonic(&tr("_Cancel"));
+    let app_clone = Rc::clone(&app);
+    settings_cancel.connect_clicked(move |_| {
+        app_clone.stack.set_visible_child(&app_clone.stack_main);
+    });
+    settings_controls.add(&settings_cancel);
+
+    let settings_ok = Button::new_with_mnemonic(&tr("_Ok"));
+
+    let app_clone = Rc::clone(&app);
+    settings_ok.connect_clicked(move |_| {
+        let log_enabled = app_clone.stack_settings.log_enabled.get_active();
+        let log_format  = app_clone.stack_settings.log_format.get_text().unwrap_or_default();
+
+        let time_clock24 = app_clone.stack_settings.time_clock24.get_active();
+        let time_dates   = app_clone.stack_settings.time_dates.get_active_id()
+                                .and_then(|id| Dates::from_id(&id))
+                                .unwrap_or(Dates::Relative);
+        let time_custom  = app_clone.stack_settings.time_custom.get_text().unwrap_or_default();
+
+        if !messages::valid_strftime(&log_format) {
+            alert(&app_clone.window, MessageType::Error, &tr("Invalid log line format"));
+            return;
+        }
+        if time_dates == Dates::Custom && !messages::valid_strftime(&time_custom) {
+            alert(&app_clone.window, MessageType::Error, &tr("Invalid timestamp pattern"));
+            return;
+        }
+
+        app_clone.stack.set_visible_child(&app_clone.stack_main);
+
+        set_data(&app_clone.db, "log_enabled", if log_enabled { "1" } else { "0" });
+        set_data(&app_clone.db, "log_format", &log_format);
+
+        *app_clone.connections.logger.lock().unwrap() = load_logger(&app_clone.db, &app_clone.basedirs);
+
+        set_data(&app_clone.db, "time_clock", if time_clock24 { "24" } else { "12" });
+        set_data(&app_clone.db, "time_dates", time_dates.id());
+        if messages::valid_strftime(&time_custom) {
+            set_data(&app_clone.db, "time_custom", &time_custom);
+        }
+
+        *app_clone.time_format.borrow_mut() = load_time_format(&app_clone.db);
+
+        let deleted_keep = app_clone.stack_settings.deleted_keep.get_active();
+        let deleted_reveal = app_clone.stack_settings.deleted_reveal.get_active();
+        set_data(&app_clone.db, "deleted_keep", if deleted_keep { "1" } else { "0" });
+        set_data(&app_clone.db, "deleted_reveal", if deleted_reveal { "1" } else { "0" });
+        load_deleted_prefs(&app_clone);
+
+        rerender_messages(&app_clone);
+    });
+
+    settings_controls.add(&settings_ok);
+    app.stack_settings.container.add(&settings_controls);
+
     app.window.add(&app.stack);
 
     // Load CSS
@@ -827,25 +1574,21 @@ fn main() {
         None => eprintln!("error: no default screen"),
         Some(screen) => {
             let css = CssProvider::new();
-            let result: Result<(), Error> = if let Some(file) = basedirs.find_config_file("style.css") {
+            let result: Result<(), Error> = if let Some(file) = app.basedirs.find_config_file("style.css") {
                 if let Some(s) = file.to_str() {
                     css.load_from_path(s).map_err(Error::from)
                 } else {
                     Err(UnicodePathError.into())
                 }
             } else {
-                let dark = if let Some(settings) = app.window.get_settings() {
-                    settings.get_property_gtk_application_prefer_dark_theme()
-                } else { false };
-
-                css.load_from_data(if dark {
+                css.load_from_data(if app.theme == Theme::Dark {
                     include_bytes!("dark.css")
                 } else {
                     include_bytes!("light.css")
                 }).map_err(Error::from)
             };
             if let Err(err) = result {
-                let string = format!("failed to load css: {}", err);
+                let string = fill(&tr("failed to load css: {}"), &[&err.to_string()]);
                 alert(&app.window, MessageType::Error, &string);
             }
             StyleContext::add_provider_for_screen(&screen, &css, STYLE_PROVIDER_PRIORITY_APPLICATION);
@@ -853,11 +1596,38 @@ fn main() {
     }
 
     app.window.show_all();
-    app.window.connect_delete_event(|_, _| {
+    let app_clone = Rc::clone(&app);
+    app.window.connect_delete_event(move |_, _| {
+        save_draft(&app_clone);
         gtk::main_quit();
         Inhibit(false)
     });
 
+    // A synac:// link can be passed as the first argument, to open it on startup
+    if let Some(arg) = env::args().nth(1) {
+        match Permalink::parse(&arg) {
+            Ok(link) => {
+                let app_clone = Rc::clone(&app);
+                gtk::idle_add(move || {
+                    open_permalink(&app_clone, &link);
+                    Continue(false)
+                });
+            },
+            Err(err) => eprintln!("invalid link {}: {}", arg, err)
+        }
+    }
+
+    // Day separators say "Today" and "Yesterday", so relabel them once the date changes
+    let app_clone = Rc::clone(&app);
+    let mut today = messages::today();
+    gtk::timeout_add_seconds(60, move || {
+        if messages::today() != today {
+            today = messages::today();
+            refresh_days(&app_clone);
+        }
+        Continue(true)
+    });
+
     gtk::timeout_add(10, move || {
         let mut channels = false;
         let mut messages = false;
@@ -867,6 +1637,17 @@ fn main() {
 
         if let Err(err) = app.connections.try_read(|synac, packet, channel_id| {
             println!("received {:?}", packet);
+            if let Packet::MessageReceive(ref e) = packet {
+                // Bots reply with regular messages, so show them next to what we sent
+                let msg = &e.inner;
+                if e.new && app.bot_console.invoked.borrow().contains(&(synac.addr, msg.author)) {
+                    let author = synac.state.users.get(&msg.author).map(|user| &*user.name).unwrap_or("unknown");
+                    log_bot_console(&app, synac.addr, &String::from_utf8_lossy(&msg.text), Some(author));
+                }
+            }
+            if let Packet::PMReceive(ref e) = packet {
+                receive_private(&app, synac, e.author, &e.text);
+            }
             if current_server != Some(synac.addr) {
                 return;
             }
@@ -877,10 +1658,24 @@ fn main() {
                 Packet::MessageDeleteReceive(_) => messages = true,
                 Packet::MessageListReceived => {
                     messages = true;
-                    scroll_to_bottom(&app);
+                    if fill_gap(&app, synac) {
+                        // Newer messages being filled in below where the link pointed
+                    } else if app.scroll_target.borrow().is_some() {
+                        scroll_to_target(&app);
+                    } else if app.loading_older.get() {
+                        // Older messages are about to be prepended,
+                        // so keep whatever is on screen in place.
+                        app.loading_older.set(false);
+                        set_scroll_anchor(&app);
+                    } else {
+                        scroll_to_bottom(&app);
+                    }
                 }
                 Packet::MessageReceive(e) => {
                     messages = e.new;
+                    if !e.new {
+                        count_gap(&app, synac.addr, &e.inner);
+                    }
 
                     let msg = &e.inner;
                     if e.new && msg.author != synac.user && !app.window.is_active() {
@@ -895,10 +1690,14 @@ fn main() {
                                 ).unwrap();
 
                                 if count == 0 {
+                                    // Notification servers may interpret the body as markup
+                                    let mut body = String::with_capacity(msg.text.len());
+                                    markup::escape(&mut body, &messages::sanitize(&msg.text));
+
                                     let result =
                                         Notification::new()
                                             .summary(&format!("{} (#{})", author.name, channel.name))
-                                            .body(&*String::from_utf8_lossy(&msg.text))
+                                            .body(&body)
                                             .show();
                                     if let Err(err) = result {
                                         eprintln!("error showing notification: {}", err);
@@ -925,12 +1724,18 @@ fn main() {
                     render_channels(&app, Some(synac));
                 } else if messages {
                     render_messages(&app, Some(synac));
-                } else if users {
+                }
+                if users {
                     render_users(&app,    Some(synac));
+                    render_bots(&app,     Some(synac));
+                    // Rows are only rebuilt when something changed, which includes their author's name
+                    render_messages(&app, Some(synac));
+                    render_private(&app, synac);
                 }
 
                 if let Some(typing) = synac.typing.check(synac.current_channel, &synac.state) {
                     app.typing.set_text(&typing);
+                    render_typing(&app, synac);
                 }
             });
         }
@@ -0,0 +1,519 @@
+use highlight::{self, Theme};
+use pulldown_cmark::{Event, Parser as MDParser, Tag};
+use std::mem;
+
+const QUOTE: &str = "<span foreground=\"#808080\">┃</span> ";
+const RULE: &str = "――――――――――";
+
+/// Schemes of URLs that are turned into links when they appear in plain text.
+pub const SCHEMES: &[&str] = &["http://", "https://", "synac://"];
+
+/// Escapes text so it's safe to put anywhere in Pango markup,
+/// including attribute values.
+pub fn escape(output: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&#39;"),
+            c => output.push(c)
+        }
+    }
+}
+
+/// Finds the first URL in `text`, returning its byte range.
+/// Trailing punctuation is left out, and so is a closing parenthesis
+/// unless the URL itself opened one, like Wikipedia links do.
+pub fn find_url(text: &str) -> Option<(usize, usize)> {
+    let mut prev: Option<char> = None;
+    for (start, c) in text.char_indices() {
+        let boundary = prev.map(|c| !c.is_alphanumeric()).unwrap_or(true);
+        prev = Some(c);
+        if !boundary {
+            continue;
+        }
+        let scheme = match SCHEMES.iter().find(|scheme| text[start..].starts_with(**scheme)) {
+            Some(scheme) => scheme,
+            None => continue
+        };
+
+        let mut end = text[start..]
+            .find(|c: char| c.is_whitespace() || c == '<' || c == '>' || c == '"')
+            .map(|end| start + end)
+            .unwrap_or(text.len());
+        loop {
+            let url = &text[start..end];
+            match url.chars().next_back() {
+                Some(c) if ".,:;!?'".contains(c) => end -= 1,
+                Some(')') if url.matches('(').count() < url.matches(')').count() => end -= 1,
+                _ => break
+            }
+        }
+        if end > start + scheme.len() {
+            return Some((start, end));
+        }
+    }
+    None
+}
+
+struct Renderer {
+    output: String,
+
+    code: Option<(String, String)>,
+    fresh: bool,
+    links: usize,
+    lists: Vec<Option<usize>>,
+    pending: String,
+    quote: usize,
+    theme: Theme,
+    urls: Vec<String>,
+    verbatim: bool
+}
+impl Renderer {
+    fn prefix(&mut self, depth: usize) {
+        for _ in 0..self.quote {
+            self.output.push_str(QUOTE);
+        }
+        for _ in 0..depth {
+            self.output.push_str("  ");
+        }
+    }
+    fn newline(&mut self) {
+        let depth = self.lists.len();
+        self.output.push('\n');
+        self.prefix(depth);
+    }
+    /// Separates a new block from whatever came before it.
+    /// Blocks are separated by an empty line, except inside lists.
+    fn block(&mut self, gap: bool, depth: usize) {
+        if self.fresh {
+            self.fresh = false;
+            return;
+        }
+        if self.output.is_empty() {
+            self.prefix(depth);
+            return;
+        }
+        if gap && self.lists.is_empty() {
+            self.output.push('\n');
+            for _ in 0..self.quote {
+                self.output.push_str(QUOTE);
+            }
+        }
+        self.output.push('\n');
+        self.prefix(depth);
+    }
+    fn text(&mut self, text: &str) {
+        self.fresh = false;
+        if let Some((_, ref mut code)) = self.code {
+            code.push_str(text);
+            return;
+        }
+        let mut lines = text.split('\n');
+        if let Some(line) = lines.next() {
+            self.inline(line);
+        }
+        for line in lines {
+            self.newline();
+            self.inline(line);
+        }
+    }
+    /// Escapes a line of text, turning URLs into links unless that's already done.
+    fn inline(&mut self, mut line: &str) {
+        if self.links == 0 && !self.verbatim {
+            while let Some((start, end)) = find_url(line) {
+                escape(&mut self.output, &line[..start]);
+                let url = &line[start..end];
+                self.output.push_str("<a href=\"");
+                escape(&mut self.output, url);
+                self.output.push_str("\" title=\"");
+                escape(&mut self.output, url);
+                self.output.push_str("\">");
+                escape(&mut self.output, url);
+                self.output.push_str("</a>");
+                self.urls.push(url.to_string());
+                line = &line[end..];
+            }
+        }
+        escape(&mut self.output, line);
+    }
+    /// The parser splits text around characters that could have been markdown,
+    /// so consecutive pieces are collected first to not cut URLs in half.
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            let text = mem::replace(&mut self.pending, String::new());
+            self.text(&text);
+        }
+    }
+    /// Like `text`, but colors each token the way the grammar says.
+    /// Spans are closed before every newline so quote prefixes don't inherit the color.
+    fn highlighted(&mut self, code: &str, grammar: &highlight::Grammar) {
+        for (kind, token) in highlight::tokens(code, grammar) {
+            let color = kind.color(self.theme);
+            let mut lines = token.split('\n');
+            if let Some(line) = lines.next() {
+                self.colored(line, color);
+            }
+            for line in lines {
+                self.newline();
+                self.colored(line, color);
+            }
+        }
+    }
+    fn colored(&mut self, text: &str, color: Option<&str>) {
+        match color {
+            Some(color) if !text.is_empty() => {
+                self.output.push_str("<span foreground=\"");
+                self.output.push_str(color);
+                self.output.push_str("\">");
+                escape(&mut self.output, text);
+                self.output.push_str("</span>");
+            },
+            _ => escape(&mut self.output, text)
+        }
+    }
+    fn start(&mut self, tag: Tag) {
+        let depth = self.lists.len();
+        match tag {
+            Tag::Paragraph => self.block(true, depth),
+            Tag::Header(level) => {
+                self.block(true, depth);
+                self.output.push_str(match level {
+                    1 => "<span size=\"xx-large\" weight=\"bold\">",
+                    2 => "<span size=\"x-large\" weight=\"bold\">",
+                    3 => "<span size=\"large\" weight=\"bold\">",
+                    _ => "<span weight=\"bold\">"
+                });
+            },
+            Tag::Rule => {
+                self.block(true, depth);
+                self.output.push_str(RULE);
+            },
+            Tag::BlockQuote => {
+                self.block(true, depth);
+                self.quote += 1;
+                self.output.push_str(QUOTE);
+                self.fresh = true;
+            },
+            Tag::CodeBlock(lang) => {
+                self.block(true, depth);
+                self.code = Some((lang.into_owned(), String::new()));
+            },
+            Tag::List(start) => {
+                if self.lists.is_empty() {
+                    self.block(true, 0);
+                    self.fresh = true;
+                }
+                self.lists.push(start);
+            },
+            Tag::Item => {
+                self.block(false, depth.saturating_sub(1));
+                match self.lists.last_mut() {
+                    Some(&mut Some(ref mut n)) => {
+                        self.output.push_str(&n.to_string());
+                        self.output.push_str(". ");
+                        *n += 1;
+                    },
+                    _ => self.output.push_str("• ")
+                }
+                self.fresh = true;
+            },
+            Tag::Emphasis => self.output.push_str("<i>"),
+            Tag::Strong => self.output.push_str("<b>"),
+            Tag::Code => {
+                self.verbatim = true;
+                self.output.push_str("<tt>");
+            },
+            Tag::Link(url, title) |
+            Tag::Image(url, title) => {
+                self.fresh = false;
+                self.links += 1;
+                // GTK+ doesn't allow links inside links, like an image inside one
+                if self.links == 1 {
+                    // Always show where a link goes on hover, whatever its text says
+                    self.output.push_str("<a href=\"");
+                    escape(&mut self.output, &url);
+                    self.output.push_str("\" title=\"");
+                    if !title.is_empty() {
+                        escape(&mut self.output, &title);
+                        self.output.push_str(" (");
+                    }
+                    escape(&mut self.output, &url);
+                    if !title.is_empty() {
+                        self.output.push(')');
+                    }
+                    self.output.push_str("\">");
+                    self.urls.push(url.into_owned());
+                }
+            },
+            _ => ()
+        }
+    }
+    fn end(&mut self, tag: Tag) {
+        match tag {
+            Tag::Header(_) => self.output.push_str("</span>"),
+            Tag::BlockQuote => self.quote -= 1,
+            Tag::CodeBlock(_) => {
+                let (lang, code) = self.code.take().unwrap_or_default();
+                let code = code.trim_right_matches('\n');
+                self.output.push_str("<tt>");
+                match highlight::find(&lang) {
+                    Some(grammar) => {
+                        self.fresh = false;
+                        self.highlighted(code, grammar);
+                    },
+                    None => self.text(code)
+                }
+                self.output.push_str("</tt>");
+            },
+            Tag::List(_) => { self.lists.pop(); },
+            Tag::Emphasis => self.output.push_str("</i>"),
+            Tag::Strong => self.output.push_str("</b>"),
+            Tag::Code => {
+                self.verbatim = false;
+                self.output.push_str("</tt>");
+            },
+            Tag::Link(..) |
+            Tag::Image(..) => {
+                self.links -= 1;
+                if self.links == 0 {
+                    self.output.push_str("</a>");
+                }
+            },
+            _ => ()
+        }
+    }
+}
+
+fn run(input: &str, theme: Theme) -> Renderer {
+    let mut renderer = Renderer {
+        output: String::with_capacity(input.len()),
+
+        code: None,
+        fresh: false,
+        links: 0,
+        lists: Vec::new(),
+        pending: String::new(),
+        quote: 0,
+        theme: theme,
+        urls: Vec::new(),
+        verbatim: false
+    };
+
+    for event in MDParser::new(input) {
+        match event {
+            Event::Text(text) |
+            Event::Html(text) |
+            Event::InlineHtml(text) => {
+                renderer.pending.push_str(&text);
+                continue;
+            },
+            Event::FootnoteReference(name) => {
+                renderer.pending.push_str("[^");
+                renderer.pending.push_str(&name);
+                renderer.pending.push_str("]");
+                continue;
+            },
+            _ => ()
+        }
+        renderer.flush();
+        match event {
+            Event::Start(tag) => renderer.start(tag),
+            Event::End(tag) => renderer.end(tag),
+            Event::SoftBreak |
+            Event::HardBreak => renderer.newline(),
+            _ => ()
+        }
+    }
+    renderer.flush();
+
+    let len = renderer.output.trim_right().len();
+    renderer.output.truncate(len);
+    renderer
+}
+/// Renders markdown to markup that GTK+ labels understand.
+/// Everything that isn't markdown, including raw HTML, is escaped.
+/// Fenced code blocks with a known language are highlighted for `theme`.
+pub fn render(input: &str, theme: Theme) -> String {
+    run(input, theme).output
+}
+/// Returns the target of every link, written out or detected, in order.
+pub fn links(input: &str) -> Vec<String> {
+    run(input, Theme::Dark).urls
+}
+
+/// Returns the contents of every code block, for copying them.
+pub fn code_blocks(input: &str) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut current: Option<String> = None;
+
+    for event in MDParser::new(input) {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => current = Some(String::new()),
+            Event::End(Tag::CodeBlock(_)) => if let Some(code) = current.take() {
+                blocks.push(code.trim_right_matches('\n').to_string());
+            },
+            Event::Text(text) => if let Some(ref mut code) = current {
+                code.push_str(&text);
+            },
+            _ => ()
+        }
+    }
+
+    blocks
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    let render = |input: &str| render(input, Theme::Dark);
+
+    assert_eq!(render("hello world"), "hello world");
+    assert_eq!(render("a < b && c > \"d\""), "a &lt; b &amp;&amp; c &gt; &quot;d&quot;");
+    assert_eq!(render("*em* **strong** `code`"), "<i>em</i> <b>strong</b> <tt>code</tt>");
+    assert_eq!(render("`<b>`"), "<tt>&lt;b&gt;</tt>");
+    assert_eq!(render("a <b>x</b>"), "a &lt;b&gt;x&lt;/b&gt;");
+    assert_eq!(render("one\ntwo"), "one\ntwo");
+    assert_eq!(render("one\n\ntwo"), "one\n\ntwo");
+    assert_eq!(render("# Title"), "<span size=\"xx-large\" weight=\"bold\">Title</span>");
+    assert_eq!(render("- a\n- b"), "• a\n• b");
+    assert_eq!(render("1. a\n2. b"), "1. a\n2. b");
+    assert_eq!(render("- a\n  - b"), "• a\n  • b");
+    assert_eq!(render("text\n\n- a"), "text\n\n• a");
+    assert_eq!(
+        render("> quoted\n\nafter"),
+        format!("{}quoted\n\nafter", QUOTE)
+    );
+    assert_eq!(render("```\nfn main() {}\nlet x = 1 < 2;\n```"), "<tt>fn main() {}\nlet x = 1 &lt; 2;</tt>");
+    assert_eq!(
+        render("[link](http://example.com/?a=1&b=2)"),
+        "<a href=\"http://example.com/?a=1&amp;b=2\" title=\"http://example.com/?a=1&amp;b=2\">link</a>"
+    );
+    assert_eq!(
+        render("[link](http://example.com \"Title\")"),
+        "<a href=\"http://example.com\" title=\"Title (http://example.com)\">link</a>"
+    );
+    assert_eq!(
+        render("see https://en.wikipedia.org/wiki/Rust_(programming_language)."),
+        "see <a href=\"https://en.wikipedia.org/wiki/Rust_(programming_language)\" \
+         title=\"https://en.wikipedia.org/wiki/Rust_(programming_language)\">\
+         https://en.wikipedia.org/wiki/Rust_(programming_language)</a>."
+    );
+    assert_eq!(render("(synac://a)"), "(<a href=\"synac://a\" title=\"synac://a\">synac://a</a>)");
+    assert_eq!(render("`http://a.b` xhttp://a.b http://"), "<tt>http://a.b</tt> xhttp://a.b http://");
+    assert_eq!(
+        render("[![img](http://a)](http://b)"),
+        "<a href=\"http://b\" title=\"http://b\">img</a>"
+    );
+    assert_eq!(links("[a](http://a) and https://b, `http://c`"), &["http://a", "https://b"]);
+    assert_eq!(
+        render("```rust\nlet x;\n```"),
+        "<tt><span foreground=\"#C678DD\">let</span> x;</tt>"
+    );
+    assert_eq!(
+        render("> ```sh\n> # a\n> # b\n> ```"),
+        format!("{0}<tt><span foreground=\"#7F848E\"># a</span>\n{0}<span foreground=\"#7F848E\"># b</span></tt>", QUOTE)
+    );
+    assert_eq!(code_blocks("a\n\n```\nx\n```\n\n    y\n"), &["x", "y"]);
+
+    // Fuzzing: whatever the input, set_markup must never be handed invalid markup.
+    fn entity(text: &str) -> Option<usize> {
+        ["&amp;", "&lt;", "&gt;", "&quot;", "&#39;"].iter()
+            .find(|entity| text.starts_with(*entity))
+            .map(|entity| entity.len())
+    }
+    fn valid_text(text: &str) -> bool {
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            match c {
+                '<' | '>' | '"' => return false,
+                '&' => match entity(rest) {
+                    Some(len) => { rest = &rest[len..]; continue; },
+                    None => return false
+                },
+                _ => ()
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+        true
+    }
+    fn valid(markup: &str) -> bool {
+        let mut stack = Vec::new();
+        let mut rest = markup;
+        while let Some(start) = rest.find('<') {
+            if !valid_text(&rest[..start]) {
+                return false;
+            }
+            let end = match rest[start..].find('>') {
+                Some(end) => start + end,
+                None => return false
+            };
+            let tag = &rest[start+1..end];
+            rest = &rest[end+1..];
+
+            if tag.starts_with('/') {
+                if stack.pop() != Some(&tag[1..]) {
+                    return false;
+                }
+                continue;
+            }
+            let name = tag.split(' ').next().unwrap();
+            if !["a", "b", "i", "span", "tt"].contains(&name) || (name == "a" && stack.contains(&"a")) {
+                return false;
+            }
+            let mut attrs = tag[name.len()..].trim_left();
+            while !attrs.is_empty() {
+                let eq = match attrs.find("=\"") {
+                    Some(eq) => eq,
+                    None => return false
+                };
+                if !attrs[..eq].chars().all(|c| c.is_alphabetic()) {
+                    return false;
+                }
+                let value = &attrs[eq+2..];
+                let close = match value.find('"') {
+                    Some(close) => close,
+                    None => return false
+                };
+                if !valid_text(&value[..close]) {
+                    return false;
+                }
+                attrs = value[close+1..].trim_left();
+            }
+            stack.push(name);
+        }
+        valid_text(rest) && stack.is_empty()
+    }
+
+    assert!(valid(&render("<b>x</b> & \"y\"")));
+    assert!(!valid("a < b"));
+    assert!(!valid("<b><i>x</b></i>"));
+    assert!(!valid("<span foreground=\"a\"b\">x</span>"));
+
+    const PIECES: &[&str] = &[
+        "*", "**", "_", "`", "```", "```rust\n", "```sh\n", "~~~", "#", "## ", ">", "> ", "- ", "1. ", "+ ",
+        "[", "]", "(", ")", "![", "<", ">", "&", "&amp;", "&#", "\"", "'", "\\", "\n", "\n\n", " ", "    ",
+        "a", "word", "<b>", "</span>", "<a href=\"x\">", "http://example.com", "[^1]", "|", "---", "===",
+        "\u{202E}", "\u{200B}", "ü", "┃", "\t", "$x", "// c", "/*", "\"s"
+    ];
+    let mut seed: u32 = 0x1234_5678;
+    let mut random = || {
+        // xorshift32, so failures are reproducible
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed
+    };
+    for _ in 0..5000 {
+        let mut input = String::new();
+        for _ in 0..random() % 40 {
+            input.push_str(PIECES[random() as usize % PIECES.len()]);
+        }
+        for &theme in &[Theme::Dark, Theme::Light] {
+            let output = self::render(&input, theme);
+            assert!(valid(&output), "invalid markup {:?} for input {:?}", output, input);
+        }
+    }
+}
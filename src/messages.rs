@@ -1,16 +1,24 @@
+use chrono::format::{Item, StrftimeItems};
 use chrono::prelude::*;
-use pulldown_cmark::{html as md_html, Parser as MDParser};
-use std::collections::HashMap;
+use i18n::{fill, tr};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
+use std::mem;
 use synac::common::Message;
 
 pub struct Messages {
-    messages: HashMap<usize, Vec<Message>>
+    messages: HashMap<usize, Vec<Message>>,
+    /// Previous versions of edited messages, oldest first
+    history: HashMap<usize, Vec<Message>>,
+    /// Messages that were deleted but kept as placeholders
+    deleted: HashSet<usize>
 }
 impl Messages {
     pub fn new() -> Self {
         Messages {
-            messages: HashMap::new()
+            messages: HashMap::new(),
+            history: HashMap::new(),
+            deleted: HashSet::new()
         }
     }
     pub fn add(&mut self, msg: Message) {
@@ -25,7 +33,14 @@ impl Messages {
                 loop {
                     let message = messages.get_mut(i);
                     if message.as_ref().map(|msg| msg.id) == Some(msg.id) {
-                        *message.unwrap() = msg;
+                        let message = message.unwrap();
+                        // Messages are also resent when history is reloaded
+                        if message.text != msg.text {
+                            let old = mem::replace(message, msg);
+                            self.history.entry(old.id).or_insert_with(Vec::new).push(old);
+                        } else {
+                            *message = msg;
+                        }
                         return;
                     }
                     if message.map(|msg| msg.timestamp) != original_timestamp {
@@ -39,6 +54,8 @@ impl Messages {
         messages.insert(i, msg);
     }
     pub fn remove(&mut self, id: usize) -> Option<usize> {
+        self.history.remove(&id);
+        self.deleted.remove(&id);
         for (channel, messages) in &mut self.messages {
             if let Some(i) = messages.iter().position(|msg| msg.id == id) {
                 messages.remove(i);
@@ -47,6 +64,39 @@ impl Messages {
         }
         None
     }
+    /// Keeps a deleted message around so it can be shown as a placeholder.
+    pub fn mark_deleted(&mut self, id: usize) -> Option<usize> {
+        let channel = self.find(id)?.channel;
+        self.deleted.insert(id);
+        Some(channel)
+    }
+    pub fn is_deleted(&self, id: usize) -> bool {
+        self.deleted.contains(&id)
+    }
+    /// Removes every message that's only kept as a placeholder.
+    pub fn purge_deleted(&mut self) {
+        for id in mem::replace(&mut self.deleted, HashSet::new()) {
+            self.remove(id);
+        }
+    }
+    /// Forgets the messages of a channel, until older ones are loaded again.
+    pub fn clear(&mut self, channel: usize) {
+        if let Some(messages) = self.messages.get_mut(&channel) {
+            for msg in messages.drain(..) {
+                self.history.remove(&msg.id);
+                self.deleted.remove(&msg.id);
+            }
+        }
+    }
+    pub fn find(&self, id: usize) -> Option<&Message> {
+        self.messages.values()
+            .flat_map(|messages| messages.iter())
+            .find(|msg| msg.id == id)
+    }
+    /// Returns the versions of a message seen before its latest edit, oldest first.
+    pub fn history(&self, id: usize) -> &[Message] {
+        self.history.get(&id).map(|inner| &*inner as &[Message]).unwrap_or(&[])
+    }
     pub fn get(&self, channel: usize) -> &[Message] {
         self.messages.get(&channel).map(|inner| &*inner as &[Message]).unwrap_or(&[])
     }
@@ -55,30 +105,172 @@ impl Messages {
     }
 }
 
-pub fn format_timestamp(output: &mut String, timestamp: i64) {
-    let time  = Utc.timestamp(timestamp, 0);
-    let local = time.with_timezone(&Local);
-    let now   = Local::now();
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dates {
+    Relative,
+    Absolute,
+    Iso,
+    Custom
+}
+impl Dates {
+    pub fn id(self) -> &'static str {
+        match self {
+            Dates::Relative => "relative",
+            Dates::Absolute => "absolute",
+            Dates::Iso => "iso",
+            Dates::Custom => "custom"
+        }
+    }
+    pub fn from_id(id: &str) -> Option<Self> {
+        match id {
+            "relative" => Some(Dates::Relative),
+            "absolute" => Some(Dates::Absolute),
+            "iso" => Some(Dates::Iso),
+            "custom" => Some(Dates::Custom),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TimeFormat {
+    pub clock24: bool,
+    pub dates: Dates,
+    pub custom: String
+}
+impl Default for TimeFormat {
+    fn default() -> Self {
+        TimeFormat {
+            clock24: false,
+            dates: Dates::Relative,
+            custom: String::from("%Y-%m-%d %H:%M")
+        }
+    }
+}
+
+/// Returns false if chrono would fail to format the pattern,
+/// which would otherwise panic once something is formatted with it.
+pub fn valid_strftime(format: &str) -> bool {
+    !StrftimeItems::new(format).any(|item| match item {
+        Item::Error => true,
+        _ => false
+    })
+}
+
+const WEEKDAYS: [&str; 7] = ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"];
+const MONTHS: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June",
+    "July", "August", "September", "October", "November", "December"
+];
 
-    match now.num_days_from_ce() - local.num_days_from_ce() {
-        0 => output.push_str("Today"),
-        1 => output.push_str("Yesterday"),
-        2 => output.push_str("Two days ago"),
-        3 => output.push_str("Three days ago"),
-        x if x < 7 => output.push_str("A few days ago"),
-        7 => output.push_str("A week ago"),
+fn local(timestamp: i64) -> DateTime<Local> {
+    Utc.timestamp(timestamp, 0).with_timezone(&Local)
+}
+/// Changes at midnight, for noticing when "Today" stops being correct.
+pub fn today() -> i32 {
+    Local::now().num_days_from_ce()
+}
+pub fn same_day(a: i64, b: i64) -> bool {
+    local(a).date() == local(b).date()
+}
+pub fn format_date(output: &mut String, timestamp: i64, format: &TimeFormat) {
+    let local = local(timestamp);
+
+    if format.dates == Dates::Iso {
+        write!(output, "{}", local.format("%Y-%m-%d")).unwrap();
+        return;
+    }
+    if format.dates == Dates::Relative {
+        match Local::now().num_days_from_ce() - local.num_days_from_ce() {
+            0 => { output.push_str(&tr("Today")); return; },
+            1 => { output.push_str(&tr("Yesterday")); return; },
+            _ => ()
+        }
+    }
+    let weekday = tr(WEEKDAYS[local.weekday().num_days_from_monday() as usize]);
+    let month   = tr(MONTHS[local.month0() as usize]);
+    output.push_str(&fill(
+        &tr("{0}, {1} {2}, {3}"),
+        &[&weekday, &month, &local.day().to_string(), &local.year().to_string()]
+    ));
+}
+pub fn format_time(output: &mut String, timestamp: i64, format: &TimeFormat) {
+    let local = local(timestamp);
+
+    match format.dates {
+        Dates::Custom => write!(output, "{}", local.format(&format.custom)).unwrap(),
+        Dates::Iso => write!(output, "{}", local.format("%H:%M")).unwrap(),
+        _ if format.clock24 => write!(output, "{}", local.format("%H:%M")).unwrap(),
         _ => {
-            write!(output, "{}-{}-{}", local.year(), local.month(), local.day()).unwrap();
+            let (is_pm, hour) = local.hour12();
+            write!(output, "{}:{:02} ", hour, local.minute()).unwrap();
+            output.push_str(&tr(if is_pm { "PM" } else { "AM" }));
         }
     }
-    output.push_str(" at ");
-    let (is_pm, hour) = local.hour12();
-    write!(output, "{}:{:02} ", hour, local.minute()).unwrap();
-    output.push_str(if is_pm { "PM" } else { "AM" });
 }
-pub fn markdown(input: &str) -> String {
-    let mut output = String::with_capacity(input.len());
-    md_html::push_html(&mut output, MDParser::new(&input));
+pub fn format_timestamp(output: &mut String, timestamp: i64, format: &TimeFormat) {
+    match format.dates {
+        // A custom pattern decides for itself whether to show the date
+        Dates::Custom => format_time(output, timestamp, format),
+        Dates::Iso => {
+            format_date(output, timestamp, format);
+            output.push(' ');
+            format_time(output, timestamp, format);
+        },
+        _ => {
+            let mut date = String::with_capacity(16);
+            let mut time = String::with_capacity(8);
+            format_date(&mut date, timestamp, format);
+            format_time(&mut time, timestamp, format);
+            output.push_str(&fill(&tr("{} at {}"), &[&date, &time]));
+        }
+    }
+}
+pub fn format_exact(output: &mut String, timestamp: i64) {
+    write!(output, "{}", local(timestamp).format("%Y-%m-%d %H:%M:%S %:z")).unwrap();
+}
+
+/// Returns true for characters that can disguise text without being seen,
+/// like bidi overrides, zero-width spaces and other control characters.
+fn invisible(c: char) -> bool {
+    match c {
+        // Zero-width (non-)joiners are needed for emoji sequences and some scripts
+        '\u{200C}' | '\u{200D}' | '\n' | '\t' => false,
+        '\u{00AD}' | '\u{061C}' | '\u{180E}' | '\u{FEFF}'
+            | '\u{200B}'...'\u{200F}'
+            | '\u{202A}'...'\u{202E}'
+            | '\u{2060}'...'\u{2064}'
+            | '\u{2066}'...'\u{2069}' => true,
+        c => c.is_control()
+    }
+}
+/// Decodes untrusted message bytes and replaces invisible characters with a visible
+/// marker like `[U+202E]`, so they can't reorder or hide what's displayed.
+/// The result is plain text and still needs escaping before being used as markup.
+pub fn sanitize(text: &[u8]) -> String {
+    let text = String::from_utf8_lossy(text);
+    let mut output = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if c == '\r' {
+            continue;
+        }
+        if invisible(c) {
+            write!(output, "[U+{:04X}]", c as u32).unwrap();
+        } else {
+            output.push(c);
+        }
+    }
 
     output
 }
+
+#[cfg(test)]
+#[test]
+fn test() {
+    assert_eq!(sanitize(b"hello\r\nworld"), "hello\nworld");
+    assert_eq!(sanitize("abc\u{202E}fed".as_bytes()), "abc[U+202E]fed");
+    assert_eq!(sanitize("a\u{200B}b\u{0}".as_bytes()), "a[U+200B]b[U+0000]");
+    assert_eq!(sanitize("👨\u{200D}👩".as_bytes()), "👨\u{200D}👩");
+    assert_eq!(sanitize(b"\xFFok"), "\u{FFFD}ok");
+}
@@ -1,45 +1,184 @@
-pub fn parse(input: &str) -> Vec<String> {
+use std::mem;
+
+#[derive(Debug, Fail, PartialEq, Eq)]
+pub enum ParseError {
+    #[fail(display = "unterminated quote at column {}", _0)]
+    UnterminatedQuote(usize)
+}
+impl ParseError {
+    /// Moves the error along, for input that was cut out of a longer line.
+    pub fn shifted(self, columns: usize) -> Self {
+        match self {
+            ParseError::UnterminatedQuote(column) => ParseError::UnterminatedQuote(column + columns)
+        }
+    }
+}
+
+/// Splits input into arguments, a bit like a shell would.
+///
+/// - `"double quotes"` may contain `\"` and `\\`
+/// - `'single quotes'` are taken literally
+/// - a backslash escapes whitespace, quotes and itself, and is kept before anything else
+/// - quotes only count at the start of an argument, or right after `--flag=`,
+///   so words like "don't" don't need escaping
+///
+/// Columns in errors count characters, starting at 1.
+pub fn parse(input: &str) -> Result<Vec<String>, ParseError> {
     let mut parts  = Vec::new();
     let mut buffer = String::new();
-    let mut escape = false;
-    let mut quote  = false;
-
-    for c in input.chars() {
-        if escape {
-            escape = false;
-            if c != '\\' && c != '"' {
-                buffer.push('\\');
-            }
-            buffer.push(c);
-        } else {
-            match c {
-                '\\' => escape = true,
-                '"' if buffer.is_empty() || quote => quote = !quote,
-                ' ' if !quote => {
-                    if !buffer.is_empty() {
-                        parts.push(buffer);
-                        buffer = String::new();
-                    }
+    let mut token  = false;
+    let mut quote: Option<(char, usize)> = None;
+
+    let mut chars = input.chars().enumerate().peekable();
+    while let Some((i, c)) = chars.next() {
+        match quote {
+            Some(('\'', _)) => if c == '\'' {
+                quote = None;
+            } else {
+                buffer.push(c);
+            },
+            Some(_) => match c {
+                '"' => quote = None,
+                '\\' => match chars.peek().map(|&(_, next)| next) {
+                    Some(next) if next == '"' || next == '\\' => {
+                        buffer.push(next);
+                        chars.next();
+                    },
+                    _ => buffer.push('\\')
                 },
                 c => buffer.push(c)
+            },
+            None => match c {
+                c if c.is_whitespace() => if token {
+                    parts.push(mem::replace(&mut buffer, String::new()));
+                    token = false;
+                },
+                '\\' => {
+                    token = true;
+                    match chars.peek().map(|&(_, next)| next) {
+                        Some(next) if next.is_whitespace() || next == '"' || next == '\'' || next == '\\' => {
+                            buffer.push(next);
+                            chars.next();
+                        },
+                        _ => buffer.push('\\')
+                    }
+                },
+                '"' | '\'' if !token || is_flag_name(&buffer) => {
+                    token = true;
+                    quote = Some((c, i + 1));
+                },
+                c => {
+                    token = true;
+                    buffer.push(c);
+                }
             }
         }
     }
 
-    if escape { buffer.push('\\'); }
-    if !buffer.is_empty() { parts.push(buffer); }
+    if let Some((_, column)) = quote {
+        return Err(ParseError::UnterminatedQuote(column));
+    }
+    if token { parts.push(buffer); }
 
-    parts
+    Ok(parts)
+}
+/// Returns true if `buffer` is `--flag=`, with the value yet to come.
+fn is_flag_name(buffer: &str) -> bool {
+    buffer.starts_with("--") && buffer.ends_with('=') && buffer.find('=') == Some(buffer.len() - 1)
+}
+/// Splits `--flag=value` into the flag's name and value.
+pub fn flag(arg: &str) -> Option<(&str, &str)> {
+    if !arg.starts_with("--") {
+        return None;
+    }
+    let eq = arg.find('=')?;
+    let name = &arg[2..eq];
+    if name.is_empty() || !is_plain(name) {
+        return None;
+    }
+    Some((name, &arg[eq+1..]))
+}
+fn is_plain(arg: &str) -> bool {
+    !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '\\')
+}
+/// Quotes an argument so `parse` reads it back unchanged.
+pub fn quote(arg: &str) -> String {
+    if is_plain(arg) {
+        return arg.to_string();
+    }
+    if let Some((name, value)) = flag(arg) {
+        return format!("--{}={}", name, quote(value));
+    }
+    if !arg.contains('\'') {
+        return format!("'{}'", arg);
+    }
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    for c in arg.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+/// Quotes and joins arguments, the opposite of `parse`.
+pub fn join<S: AsRef<str>>(args: &[S]) -> String {
+    args.iter().map(|arg| quote(arg.as_ref())).collect::<Vec<_>>().join(" ")
 }
 
 #[cfg(test)]
 #[test]
 fn test() {
-    assert_eq!(parse(r#"hello world"#), &["hello", "world"]);
-    assert_eq!(parse(r#""hello world""#), &["hello world"]);
-    assert_eq!(parse(r#"hel"lo wor"ld"#), &["hel\"lo", "wor\"ld"]);
-    assert_eq!(parse(r#"hello\ world"#), &["hello\\ world"]);
-    assert_eq!(parse(r#"\h\e\l\l\o world"#), &["\\h\\e\\l\\l\\o", "world"]);
-    assert_eq!(parse(r#"\"hello world\""#), &["\"hello", "world\""]);
-    assert_eq!(parse(r#"\\\"hello world\\\""#), &["\\\"hello", "world\\\""]);
+    assert_eq!(parse(r#"hello world"#).unwrap(), &["hello", "world"]);
+    assert_eq!(parse(r#""hello world""#).unwrap(), &["hello world"]);
+    assert_eq!(parse(r#"hel"lo wor"ld"#).unwrap(), &["hel\"lo", "wor\"ld"]);
+    assert_eq!(parse(r#"hello\ world"#).unwrap(), &["hello world"]);
+    assert_eq!(parse(r#"\h\e\l\l\o world"#).unwrap(), &["\\h\\e\\l\\l\\o", "world"]);
+    assert_eq!(parse(r#"\"hello world\""#).unwrap(), &["\"hello", "world\""]);
+    assert_eq!(parse(r#"\\\"hello world\\\""#).unwrap(), &["\\\"hello", "world\\\""]);
+
+    assert_eq!(parse(r#"'a "b" \c' don't"#).unwrap(), &["a \"b\" \\c", "don't"]);
+    assert_eq!(parse(r#"--name="John Smith" --x=1 '' """#).unwrap(), &["--name=John Smith", "--x=1", "", ""]);
+    assert_eq!(parse("  tabs\tand\nnewlines  ").unwrap(), &["tabs", "and", "newlines"]);
+    assert_eq!(parse(r#"say "hi"#), Err(ParseError::UnterminatedQuote(5)));
+    assert_eq!(parse("ü 'x"), Err(ParseError::UnterminatedQuote(3)));
+    assert_eq!(parse("'").unwrap_err().shifted(2), ParseError::UnterminatedQuote(3));
+
+    assert_eq!(flag("--name=x=y"), Some(("name", "x=y")));
+    assert_eq!(flag("--=x"), None);
+    assert_eq!(quote("--name=John Smith"), "--name='John Smith'");
+    assert_eq!(join(&["a b", "it's", ""]), r#"'a b' "it's" ''"#);
+
+    // Awkward arguments must come back unchanged through join and parse
+    let cases: &[&[&str]] = &[
+        &[],
+        &[""],
+        &["", ""],
+        &["a b", "it's", "\"quoted\""],
+        &["back\\slash", "\\", "\\\\", "\\\""],
+        &["'", "\"", "'\"", "\"'\\"],
+        &["tab\there", "new\nline", " lead", "trail "],
+        &["--f=", "--f=a b", "--f='x'", "--f=\"y\"", "--=-"],
+        &["ü€", "ü '€'", "--f=ü \\€"]
+    ];
+    for args in cases {
+        let joined = join(args);
+        assert_eq!(parse(&joined).unwrap(), *args, "{:?} was joined as {}", args, joined);
+    }
+
+    // An unterminated quote is reported at the column it opened on
+    let cases = &[
+        ("\"", 1),
+        ("a '", 3),
+        ("'a' \"b", 5),
+        ("\"it's", 1),
+        ("\\\" '", 4),
+        ("ü€ \"x", 4),
+        ("--f='x y", 5)
+    ];
+    for &(input, column) in cases {
+        assert_eq!(parse(input), Err(ParseError::UnterminatedQuote(column)), "{:?}", input);
+    }
 }
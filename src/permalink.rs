@@ -0,0 +1,141 @@
+use std::fmt;
+
+pub const SCHEME: &str = "synac://";
+
+#[derive(Debug, Fail)]
+pub enum PermalinkError {
+    #[fail(display = "not a synac:// link")]
+    NotSynac,
+    #[fail(display = "link has no server")]
+    NoHost,
+    #[fail(display = "invalid channel or message id in link")]
+    InvalidId
+}
+
+/// A link like `synac://host[:port]/channel/message?hash=...`.
+/// The hash is the server's public key hash, so following a link
+/// to an unknown server doesn't mean trusting whoever answers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Permalink {
+    pub host: String,
+    pub channel: Option<usize>,
+    pub message: Option<usize>,
+    pub hash: Option<String>
+}
+impl Permalink {
+    pub fn parse(uri: &str) -> Result<Self, PermalinkError> {
+        if !uri.get(..SCHEME.len()).map(|scheme| scheme.eq_ignore_ascii_case(SCHEME)).unwrap_or(false) {
+            return Err(PermalinkError::NotSynac);
+        }
+        let rest = &uri[SCHEME.len()..];
+        let rest = rest.split('#').next().unwrap_or("");
+
+        let mut parts = rest.splitn(2, '?');
+        let path = parts.next().unwrap_or("");
+        let query = parts.next().unwrap_or("");
+
+        let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+        let host = match segments.next() {
+            Some(host) => host.to_string(),
+            None => return Err(PermalinkError::NoHost)
+        };
+        let mut ids = segments.map(|segment| segment.parse::<usize>());
+        let channel = match ids.next() {
+            Some(Ok(id)) => Some(id),
+            Some(Err(_)) => return Err(PermalinkError::InvalidId),
+            None => None
+        };
+        let message = match ids.next() {
+            Some(Ok(id)) => Some(id),
+            Some(Err(_)) => return Err(PermalinkError::InvalidId),
+            None => None
+        };
+
+        let hash = query.split('&')
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some("hash"), Some(value)) if !value.is_empty() => Some(decode(value)),
+                    _ => None
+                }
+            })
+            .next();
+
+        Ok(Permalink {
+            host: host,
+            channel: channel,
+            message: message,
+            hash: hash
+        })
+    }
+}
+impl fmt::Display for Permalink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", SCHEME, self.host)?;
+        if let Some(channel) = self.channel {
+            write!(f, "/{}", channel)?;
+            if let Some(message) = self.message {
+                write!(f, "/{}", message)?;
+            }
+        }
+        if let Some(ref hash) = self.hash {
+            write!(f, "?hash={}", encode(hash))?;
+        }
+        Ok(())
+    }
+}
+
+fn encode(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => output.push(byte as char),
+            byte => output.push_str(&format!("%{:02X}", byte))
+        }
+    }
+    output
+}
+fn decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        // Anything that isn't % and two hex digits is kept as it is
+        if bytes[i] == b'%' && i + 2 < bytes.len()
+                && bytes[i+1].is_ascii_hexdigit() && bytes[i+2].is_ascii_hexdigit() {
+            output.push(u8::from_str_radix(&input[i+1..i+3], 16).unwrap());
+            i += 3;
+        } else {
+            output.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    let link = Permalink::parse("synac://example.com:8439/3/42?hash=ab%2Bc%3D").unwrap();
+    assert_eq!(link, Permalink {
+        host: String::from("example.com:8439"),
+        channel: Some(3),
+        message: Some(42),
+        hash: Some(String::from("ab+c="))
+    });
+    assert_eq!(link.to_string(), "synac://example.com:8439/3/42?hash=ab%2Bc%3D");
+
+    let link = Permalink::parse("SYNAC://example.com/").unwrap();
+    assert_eq!((link.channel, link.message, link.hash.clone()), (None, None, None));
+    assert_eq!(link.to_string(), "synac://example.com");
+
+    assert_eq!(Permalink::parse("synac://h/1?x=y&hash=z#frag").unwrap().hash, Some(String::from("z")));
+    assert_eq!(decode("100%"), "100%");
+    assert_eq!(decode("%+1%2"), "%+1%2");
+    assert_eq!(decode("%ü1%e2%82%AC"), "%ü1€");
+
+    assert!(Permalink::parse("http://example.com").is_err());
+    assert!(Permalink::parse("synac://").is_err());
+    assert!(Permalink::parse("ü").is_err());
+    assert!(Permalink::parse("synac://host/general").is_err());
+}
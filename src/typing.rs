@@ -1,4 +1,5 @@
-use std::collections::HashMap;
+use i18n::{fill, tr};
+use std::collections::hash_map::{HashMap, Keys};
 use std::time::{Duration, Instant};
 use synac::{common, State};
 
@@ -16,6 +17,10 @@ impl Typing {
     pub fn insert(&mut self, author: usize, channel: usize) {
         self.people.insert((author, channel), Instant::now());
     }
+    /// Returns the `(author, channel)` pairs of everyone typing, as of the last `check`.
+    pub fn people(&self) -> Keys<(usize, usize), Instant> {
+        self.people.keys()
+    }
     pub fn check(&mut self, channel: Option<usize>, state: &State) -> Option<String> {
         let typing_check = Duration::from_secs(1); // TODO: const fn
         if self.last_checked.elapsed() < typing_check {
@@ -38,13 +43,13 @@ impl Typing {
 
         Some(match people.len() {
             n if n > 500 => String::from("(╯°□°）╯︵ ┻━┻"),
-            n if n > 100 => String::from("A crap ton of people are typing"),
-            n if n > 50 => String::from("Over 50 people are typing"),
-            n if n > 10 => String::from("Over 10 people are typing"),
-            n if n > 3 => String::from("Several people are typing"),
-            3 => format!("{}, {} and {} are typing", people[0], people[1], people[2]),
-            2 => format!("{} and {} are typing", people[0], people[1]),
-            1 => format!("{} is typing", people[0]),
+            n if n > 100 => tr("A crap ton of people are typing"),
+            n if n > 50 => fill(&tr("Over {} people are typing"), &["50"]),
+            n if n > 10 => fill(&tr("Over {} people are typing"), &["10"]),
+            n if n > 3 => tr("Several people are typing"),
+            3 => fill(&tr("{}, {} and {} are typing"), &[people[0], people[1], people[2]]),
+            2 => fill(&tr("{} and {} are typing"), &[people[0], people[1]]),
+            1 => fill(&tr("{} is typing"), &[people[0]]),
             _ => String::new()
         })
     }